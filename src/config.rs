@@ -1,5 +1,7 @@
 use crate::error::ApplicationError;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
@@ -8,6 +10,19 @@ use std::path::Path;
 pub struct Config {
     listen: ConfigAddr,
     paths: ConfigPaths,
+    #[serde(default)]
+    tls: ConfigCert,
+    #[serde(default)]
+    trash: ConfigTrash,
+    #[serde(default)]
+    limits: ConfigLimits,
+    #[serde(default)]
+    session: ConfigSession,
+    #[serde(default)]
+    login: ConfigLogin,
+    /// account created on first run
+    #[serde(default)]
+    account: Account,
 }
 
 impl Default for Config {
@@ -15,6 +30,12 @@ impl Default for Config {
         Config {
             listen: ConfigAddr::default(),
             paths: ConfigPaths::default(),
+            tls: ConfigCert::default(),
+            trash: ConfigTrash::default(),
+            limits: ConfigLimits::default(),
+            session: ConfigSession::default(),
+            login: ConfigLogin::default(),
+            account: Account::default(),
         }
     }
 }
@@ -37,6 +58,15 @@ impl Config {
         format!("{}:{}", &self.listen.host, self.listen.port)
     }
 
+    pub fn tls(&self) -> &ConfigCert {
+        &self.tls
+    }
+
+    /// address of the https listener
+    pub fn tls_listen_on(&self) -> String {
+        format!("{}:{}", &self.listen.host, self.tls.port)
+    }
+
     pub fn data_root_path(&self) -> String {
         format!("{}/collections/", self.paths.root_dir)
     }
@@ -44,6 +74,26 @@ impl Config {
     pub fn auth_db_path(&self) -> String {
         format!("{}/auth.db", self.paths.root_dir)
     }
+
+    pub fn trash_retention_days(&self) -> u32 {
+        self.trash.retention_days
+    }
+
+    pub fn limits(&self) -> &ConfigLimits {
+        &self.limits
+    }
+
+    pub fn session_ttl_days(&self) -> u32 {
+        self.session.ttl_days
+    }
+
+    pub fn login(&self) -> &ConfigLogin {
+        &self.login
+    }
+
+    pub fn account(&self) -> &Account {
+        &self.account
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -74,34 +124,165 @@ impl Default for ConfigPaths {
     }
 }
 
-// #[derive(Debug, Clone, Serialize, Deserialize, Default)]
-// pub struct ConfigCert {
-//     ssl_enable: bool,
-//     pub cert_file: String,
-//     pub key_file: String,
-// }
-
-// /// account in config file
-// #[cfg(feature = "account")]
-// #[derive(Debug, Clone, Serialize, Deserialize, Default)]
-// pub struct Account {
-//     username: Option<String>,
-//     password: Option<String>,
-// }
-// #[cfg(feature = "account")]
-// impl Account {
-//     pub fn username(&self) -> Option<String> {
-//         // return Some("") if field is item="",so use filter to transform Some("") to None
-//         self.username
-//             .as_ref()
-//             .filter(|e| !e.is_empty())
-//             .map(|e| e.to_string())
-//     }
-
-//     pub fn password(&self) -> Option<String> {
-//         self.password
-//             .as_ref()
-//             .filter(|e| !e.is_empty())
-//             .map(|e| e.to_string())
-//     }
-// }
+/// deleted files are kept in trash for this many days
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigTrash {
+    retention_days: u32,
+}
+
+impl Default for ConfigTrash {
+    fn default() -> Self {
+        ConfigTrash { retention_days: 30 }
+    }
+}
+
+/// size limits of request bodies in MiB
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigLimits {
+    /// limit of methods not listed in `methods`
+    default_mb: u64,
+    /// bodies bigger than this are written to a temp file instead of kept in memory
+    spool_mb: u64,
+    /// method name as in the url,e.g. upload,applyChunk -> limit
+    methods: HashMap<String, u64>,
+}
+
+impl Default for ConfigLimits {
+    fn default() -> Self {
+        ConfigLimits {
+            default_mb: 8,
+            spool_mb: 8,
+            methods: HashMap::from([
+                ("upload".to_string(), 1024),
+                ("applyChunk".to_string(), 256),
+            ]),
+        }
+    }
+}
+
+impl ConfigLimits {
+    /// body limit of `method` in bytes
+    pub fn limit_of(&self, method: &str) -> u64 {
+        self.methods.get(method).copied().unwrap_or(self.default_mb) * MIB
+    }
+
+    pub fn spool_bytes(&self) -> u64 {
+        self.spool_mb * MIB
+    }
+}
+
+const MIB: u64 = 1024 * 1024;
+
+/// session tokens expire this many days after login,0 for never
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct ConfigSession {
+    ttl_days: u32,
+}
+
+/// failed logins allowed within `window_secs` before an ip or username is locked out
+/// for `lockout_secs`,0 to not limit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigLogin {
+    pub max_failures_per_ip: u32,
+    pub max_failures_per_user: u32,
+    pub window_secs: u64,
+    pub lockout_secs: u64,
+}
+
+impl Default for ConfigLogin {
+    fn default() -> Self {
+        ConfigLogin {
+            max_failures_per_ip: 20,
+            max_failures_per_user: 5,
+            window_secs: 900,
+            lockout_secs: 900,
+        }
+    }
+}
+
+/// https listener on `port`,next to plain http on `[listen] port` unless `http_enable` is false.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigCert {
+    pub ssl_enable: bool,
+    pub port: u16,
+    /// PEM files
+    pub cert_file: String,
+    pub key_file: String,
+    /// keep the plain http listener
+    pub http_enable: bool,
+    /// read cert and key files again on SIGHUP,e.g. after certbot renewed them
+    pub reload_on_sighup: bool,
+}
+
+impl Default for ConfigCert {
+    fn default() -> Self {
+        ConfigCert {
+            ssl_enable: false,
+            port: 27702,
+            cert_file: "cert.pem".to_string(),
+            key_file: "key.pem".to_string(),
+            http_enable: true,
+            reload_on_sighup: true,
+        }
+    }
+}
+
+/// account in config file,created when the server starts without any user.
+/// Environment variables OBSIDIANSYNCD_USERNAME and OBSIDIANSYNCD_PASSWORD take precedence.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Account {
+    username: Option<String>,
+    password: Option<String>,
+}
+impl Account {
+    pub fn username(&self) -> Option<String> {
+        // return Some("") if field is item="",so use filter to transform Some("") to None
+        env::var("OBSIDIANSYNCD_USERNAME")
+            .ok()
+            .or_else(|| self.username.clone())
+            .filter(|e| !e.is_empty())
+    }
+
+    pub fn password(&self) -> Option<String> {
+        env::var("OBSIDIANSYNCD_PASSWORD")
+            .ok()
+            .or_else(|| self.password.clone())
+            .filter(|e| !e.is_empty())
+    }
+}
+
+#[test]
+fn partial_sections_use_defaults() {
+    let c: Config = toml::from_str(
+        r#"
+[listen]
+host = "0.0.0.0"
+port = 27701
+[paths]
+root_dir = "."
+[tls]
+ssl_enable = true
+cert_file = "/etc/obsidiansyncd/cert.pem"
+key_file = "/etc/obsidiansyncd/key.pem"
+[trash]
+[login]
+max_failures_per_user = 3
+[limits]
+default_mb = 4
+"#,
+    )
+    .unwrap();
+    assert!(c.tls().ssl_enable);
+    assert_eq!(c.tls().port, 27702);
+    assert!(c.tls().http_enable);
+    assert_eq!(c.trash_retention_days(), 30);
+    assert_eq!(c.login().max_failures_per_user, 3);
+    assert_eq!(c.login().lockout_secs, 900);
+    assert_eq!(c.limits().limit_of("meta"), 4 * MIB);
+    assert_eq!(c.limits().limit_of("upload"), 1024 * MIB);
+}
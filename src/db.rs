@@ -2,28 +2,52 @@ use rusqlite::params;
 use rusqlite::Connection;
 use rusqlite::OptionalExtension;
 use rusqlite::Result;
+use rusqlite::types::Type;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::merge::merge3;
 use crate::protocol::DownloadRequest;
 use crate::protocol::DownloadResponse;
 use crate::protocol::FileAction;
+use crate::protocol::FileContent;
+use crate::protocol::FileInfo;
+use crate::protocol::HistoryRequest;
+use crate::protocol::HistoryResponse;
+use crate::protocol::RestoreRequest;
+use crate::protocol::Revision;
+use crate::protocol::TrashEntry;
+use crate::protocol::TrashResponse;
+use crate::protocol::UndeleteRequest;
+use crate::protocol::normalize_path;
 use crate::protocol::MetaInner;
 use crate::protocol::Pfile;
-use crate::protocol::UploadRequest;
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct Meta {
     id: i32,
     fname: String,
-    indexs: i32,
+    indexs: String,
     pub(crate) paths: String,
     pub(crate) states: FileAction,
     ctime: i64,
     mtime: i64,
+    /// normalized `paths`,the key of a file
+    pathkey: String,
 }
 
 impl Meta {
+    pub(crate) fn key(&self) -> String {
+        self.pathkey.to_string()
+    }
+
+    /// sha256 of content
+    pub(crate) fn hash(&self) -> String {
+        self.indexs.to_string()
+    }
+
     pub(crate) fn fname(&self) -> String {
         self.fname.to_string()
     }
@@ -50,11 +74,15 @@ fn to_meta(row: &rusqlite::Row) -> rusqlite::Result<Meta> {
         fname: row.get(1)?,
         indexs: row.get(2)?,
         paths: row.get(3)?,
-        states: serde_json::from_str(&row.get::<_, String>(4)?).unwrap(),
+        states: serde_json::from_str(&row.get::<_, String>(4)?)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(4, Type::Text, Box::new(e)))?,
         ctime: row.get(5)?,
         mtime: row.get(6)?,
+        pathkey: row.get(7)?,
     })
 }
+/// columns read by `to_meta`
+const META_COLUMNS: &str = "id, fname, indexs, paths, states, ctime, mtime, pathkey";
 
 pub(crate) struct DbManager {
     db: Db,
@@ -63,27 +91,266 @@ pub(crate) struct DbManager {
 impl DbManager {
     pub fn new(folder: &Path) -> Result<Self, rusqlite::Error> {
         let db = Db::new(folder)?;
+        let n = db.collect_garbage()?;
+        if n > 0 {
+            log::info!("removed {n} unreferenced blobs from {}", folder.display());
+        }
         Ok(Self { db })
     }
     /// get records from table meta
     pub(crate) fn get_meta(&self) -> Result<Option<Vec<Meta>>> {
         self.db.get_meta_records()
     }
-    pub(crate) fn update_stetes(&self, meta: &[MetaInner]) -> Result<()> {
-        self.db.update_meta_states(meta)
+    /// whether there is a record at `key`,deleted or not
+    pub(crate) fn has_file(&self, key: &str) -> Result<bool> {
+        self.db.has_record(key)
+    }
+    /// record at `key` unless it is deleted
+    pub(crate) fn current(&self, key: &str) -> Result<Option<Meta>> {
+        self.db.current_meta(key)
+    }
+    /// usn of the latest change,what a client passes as cursor next time
+    pub(crate) fn cursor(&self) -> Result<i64> {
+        self.db.last_usn()
+    }
+    /// Whether changes after `cursor` can no longer be told,because delete marks newer
+    /// than it were purged from trash,or it is from another db.
+    pub(crate) fn cursor_expired(&self, cursor: i64) -> Result<bool> {
+        Ok(cursor < self.db.purged_usn()? || cursor > self.db.last_usn()?)
+    }
+    /// Records changed after `cursor`,as downloads and deletes.
+    ///
+    /// Files `device` already has the content of,e.g. its own uploads,are left out.
+    pub(crate) fn changes_since(&self, cursor: i64, device: &str) -> Result<Vec<MetaInner>> {
+        let mut changes = vec![];
+        for meta in self.db.changed_since(cursor)? {
+            if meta.states == FileAction::Delete {
+                changes.push(MetaInner::new(FileAction::Delete, &meta));
+            } else if self.db.base_of(device, &meta.pathkey)?.as_deref() != Some(&meta.indexs) {
+                changes.push(MetaInner::new(FileAction::Download, &meta));
+            }
+        }
+        Ok(changes)
+    }
+    /// `device` is recorded as the one that deleted files
+    pub(crate) fn update_stetes(&self, meta: &[MetaInner], device: &str) -> Result<()> {
+        self.db.update_meta_states(meta, device)
     }
 
+    /// `device` is remembered as having the downloaded content,empty if unknown
     pub(crate) fn download(
         &self,
         req: DownloadRequest,
+        device: &str,
     ) -> Result<DownloadResponse, rusqlite::Error> {
-        self.db.retrieve_files(req)
+        self.db.retrieve_files(req, device)
+    }
+    /// Store one uploaded file,return files the client has to fetch again
+    /// because its upload was merged.
+    pub(crate) fn upload(&self, file: Pfile, device: &str) -> Result<Vec<MetaInner>> {
+        self.db.store_file(file, device)
+    }
+    /// run `f` in one transaction,committed if it returns Ok
+    pub(crate) fn in_transaction<R, E, F>(&self, f: F) -> Result<R, E>
+    where
+        F: FnOnce() -> Result<R, E>,
+        E: From<rusqlite::Error>,
+    {
+        let tx = self.db.conn.unchecked_transaction()?;
+        let r = f()?;
+        tx.commit()?;
+        Ok(r)
+    }
+    pub(crate) fn rename(&self, fileinfo: &FileInfo) -> Result<bool> {
+        self.db.rename_file(fileinfo)
+    }
+    pub(crate) fn history(&self, req: HistoryRequest) -> Result<HistoryResponse> {
+        let revisions = self.db.revisions_of(&normalize_path(&req.path))?;
+        Ok(HistoryResponse { revisions })
+    }
+    pub(crate) fn restore(&self, req: RestoreRequest) -> Result<Option<FileInfo>> {
+        self.db.restore_revision(&normalize_path(&req.path), req.revision)
+    }
+    pub(crate) fn trash(&self) -> Result<TrashResponse> {
+        let files = self.db.trash_entries()?;
+        Ok(TrashResponse { files })
+    }
+    /// return the restored files,paths not found in trash or created again since are skipped
+    pub(crate) fn undelete(&self, req: UndeleteRequest) -> Result<Vec<FileInfo>> {
+        let mut restored = vec![];
+        for path in req.paths {
+            if let Some(fileinfo) = self.db.undelete(&normalize_path(&path))? {
+                restored.push(fileinfo);
+            }
+        }
+        Ok(restored)
+    }
+    /// drop files deleted more than `retention_days` ago,return the number of them
+    pub(crate) fn purge_trash(&self, retention_days: u32) -> Result<usize> {
+        let before = now_millis() - i64::from(retention_days) * 24 * 3600 * 1000;
+        self.db.purge_trash(before)
     }
-    pub(crate) fn upload(&self, req: UploadRequest) -> Result<(), rusqlite::Error> {
-        self.db.store_files(req)
+    /// Open the chunked sync session `skey` of `device`,or keep it open.
+    ///
+    /// A client that dropped off in the middle of a session never sends abort,
+    /// so a session of the same device with another key is dropped with its files.
+    /// Sessions of other devices are left alone.
+    pub(crate) fn open_session(&self, device: &str, skey: &str) -> Result<()> {
+        self.db.open_session(device, skey)
+    }
+    /// keep a file of applyChunk aside until the session finishes
+    pub(crate) fn stage(&self, device: &str, file: &Pfile) -> Result<()> {
+        self.db.stage_file(device, file)?;
+        Ok(())
+    }
+    /// Write the files of the session to meta in one transaction,
+    /// return files to fetch again as upload does.None if `skey` is not open.
+    pub(crate) fn finish_session(
+        &self,
+        device: &str,
+        skey: &str,
+    ) -> Result<Option<Vec<MetaInner>>> {
+        self.db.finish_session(device, skey)
+    }
+    /// Throw away the files of the session.Aborting a session that is not open
+    /// is a no-op,so that clients can always abort on error.
+    pub(crate) fn abort_session(&self, device: &str, skey: &str) -> Result<()> {
+        if self.db.session_key(device)?.as_deref() == Some(skey) {
+            self.db.drop_session(device)?;
+        }
+        Ok(())
+    }
+    /// drop sessions nothing was sent to for `SESSION_IDLE`,return the number of them
+    pub(crate) fn expire_sessions(&self) -> Result<usize> {
+        self.db.expire_sessions(now_millis() - SESSION_IDLE)
     }
 }
 
+/// milliseconds a chunked session may stay idle before it is dropped
+const SESSION_IDLE: i64 = 3600 * 1000;
+
+/// stored in `PRAGMA user_version`,bump it with every step added to `upgrade`
+const SCHEMA_VERSION: u32 = 5;
+/// Bring a db created by an older server up to the current schema.
+/// A fresh db has nothing to upgrade,file.sql creates the latest tables.
+fn upgrade(conn: &Connection) -> Result<()> {
+    let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let exists: i64 = conn.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = 'meta'",
+        [],
+        |row| row.get(0),
+    )?;
+    if exists == 0 {
+        return Ok(());
+    }
+    if version < 1 {
+        upgrade_step(conn, 1, |c| c.execute_batch(include_str!("upgrades/v1.sql")))?;
+    }
+    if version < 2 {
+        upgrade_step(conn, 2, upgrade_v2)?;
+    }
+    if version < 3 {
+        upgrade_step(conn, 3, |c| c.execute_batch(include_str!("upgrades/v3.sql")))?;
+    }
+    if version < 4 {
+        upgrade_step(conn, 4, upgrade_v4)?;
+    }
+    if version < 5 {
+        upgrade_step(conn, 5, |c| c.execute_batch(include_str!("upgrades/v5.sql")))?;
+    }
+    Ok(())
+}
+/// Run one step and record its version in the same transaction,so that a crash
+/// or error never leaves a step done but not counted,to be run again on the next start.
+fn upgrade_step<F>(conn: &Connection, version: u32, step: F) -> Result<()>
+where
+    F: FnOnce(&Connection) -> Result<()>,
+{
+    let tx = conn.unchecked_transaction()?;
+    step(&tx)?;
+    tx.pragma_update(None, "user_version", version)?;
+    tx.commit()
+}
+/// content used to be keyed by meta id,rehash it and point meta.indexs to the hash.
+/// sha256 is not available in sqlite,so the id -> hash mapping is filled in here.
+fn upgrade_v2(conn: &Connection) -> Result<()> {
+    conn.execute_batch(include_str!("upgrades/v2_begin.sql"))?;
+    {
+        let mut select = conn.prepare("SELECT id, content FROM content_old")?;
+        let mut insert_content =
+            conn.prepare("INSERT OR IGNORE INTO content (hash, content) VALUES (?, ?)")?;
+        let mut insert_hash = conn.prepare("INSERT INTO id_hash (id, hash) VALUES (?, ?)")?;
+        let rows = select.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?;
+        for row in rows {
+            let (id, content) = row?;
+            let hash = content_hash(&content);
+            insert_content.execute(params![hash, content])?;
+            insert_hash.execute(params![id, hash])?;
+        }
+    }
+    conn.execute_batch(include_str!("upgrades/v2_end.sql"))
+}
+/// files used to be matched by bare name,key them by normalized full path
+fn upgrade_v4(conn: &Connection) -> Result<()> {
+    conn.execute_batch("ALTER TABLE meta ADD COLUMN pathkey TEXT NOT NULL DEFAULT ''")?;
+    let rows = conn
+        .prepare("SELECT id, fname, paths FROM meta")?
+        .query_map([], |row| {
+            let fileinfo = FileInfo {
+                name: row.get(1)?,
+                path: row.get(2)?,
+                ..Default::default()
+            };
+            Ok((row.get::<_, i64>(0)?, fileinfo.key()))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    {
+        let mut update = conn.prepare("UPDATE meta SET pathkey = ? WHERE id = ?")?;
+        for (id, key) in rows {
+            update.execute(params![key, id])?;
+        }
+    }
+    conn.execute_batch(include_str!("upgrades/v4.sql"))
+}
+/// `Dir/notes.md` -> `Dir/notes (conflicted copy phone abcd1234).md`
+fn conflicted_copy(fileinfo: &FileInfo, device: &str, hash: &str) -> FileInfo {
+    let path = fileinfo.key();
+    let device = device.replace(['/', '\\'], "-");
+    let suffix = format!(" (conflicted copy {device} {})", &hash[..8]);
+    let path = match path.rfind('.') {
+        Some(i) if !path[i..].contains('/') => format!("{}{suffix}{}", &path[..i], &path[i..]),
+        _ => format!("{path}{suffix}"),
+    };
+    FileInfo {
+        name: path.rsplit('/').next().unwrap_or_default().to_string(),
+        path,
+        oldpath: "".to_string(),
+        hash: hash.to_string(),
+        ..fileinfo.clone()
+    }
+}
+pub(crate) fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+/// file action as it is stored in meta.states
+fn state_of(action: &FileAction) -> String {
+    serde_json::to_string(action).unwrap()
+}
+/// key of a file content in table content
+pub(crate) fn content_hash(content: &[u8]) -> String {
+    hex::encode(Sha256::digest(content))
+}
+
+/// blobs left behind when the last file using them is overwritten
+const UNREFERENCED_BLOBS: &str = "SELECT hash FROM content WHERE hash NOT IN
+    (SELECT indexs FROM meta UNION SELECT hash FROM bases UNION SELECT indexs FROM revisions
+    UNION SELECT indexs FROM trash)";
+
 pub(crate) struct Db {
     conn: Connection,
 }
@@ -91,89 +358,417 @@ pub(crate) struct Db {
 impl Db {
     fn new(folder: &Path) -> Result<Self, rusqlite::Error> {
         let conn = Connection::open(folder.join("obsidian.db"))?;
-        // create table meta and content
-        conn.execute_batch(include_str!("file.sql"))?;
+        upgrade(&conn)?;
+        // create table meta and content,a fresh db is at the latest version right away
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(include_str!("file.sql"))?;
+        tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        tx.commit()?;
         Ok(Self { conn })
     }
-    fn retrieve_files(&self, req: DownloadRequest) -> Result<DownloadResponse, rusqlite::Error> {
+    fn retrieve_files(
+        &self,
+        req: DownloadRequest,
+        device: &str,
+    ) -> Result<DownloadResponse, rusqlite::Error> {
         let conn = &self.conn;
-        let mut meta_stmt = conn.prepare("SELECT * FROM meta WHERE fname =?")?;
+        let mut meta_stmt =
+            conn.prepare(&format!("SELECT {META_COLUMNS} FROM meta WHERE pathkey = ?"))?;
 
-        let mut content_stmt = conn.prepare("SELECT content FROM content WHERE id = ?")?;
         let mut files = vec![];
-        for fname in req.filenames {
-            let meta = meta_stmt.query_row(params![fname], to_meta)?;
-            let content: String = content_stmt.query_row(params![meta.indexs], |row| row.get(0))?;
+        for path in req.paths {
+            // paths the server doesn't have are left out
+            let meta = match meta_stmt
+                .query_row(params![normalize_path(&path)], to_meta)
+                .optional()?
+            {
+                Some(meta) => meta,
+                None => {
+                    log::warn!("download of unknown file {path}");
+                    continue;
+                }
+            };
+            let content = self.get_content(&meta.indexs)?;
+            self.set_base(device, &meta.pathkey, &meta.indexs)?;
 
             let pfile = Pfile {
-                states: crate::protocol::FileInfo {
-                    name: meta.fname,
-                    path: meta.paths,
-                    mtime: meta.mtime,
-                    ctime: meta.ctime,
-                    oldpath: "".to_string(),
-                },
-                content,
+                states: FileInfo::from(&meta),
+                content: FileContent(content),
             };
             files.push(pfile);
         }
         Ok(DownloadResponse { files })
     }
-    fn store_files(&self, req: UploadRequest) -> Result<(), rusqlite::Error> {
-        let conn = &self.conn;
-        //   let tx = conn.transaction()?;
-        let mut content_stmt = conn.prepare("INSERT INTO content (id, content) VALUES (?, ?)")?;
-        let mut meta_stmt  = conn.prepare("INSERT INTO meta (id, fname, indexs, paths, states, ctime, mtime) VALUES (?, ?, ?, ?, ?, ?, ?)")?;
-        let mut last_id: i32 = conn
-            .query_row("SELECT id FROM meta ORDER BY id DESC LIMIT 1", [], |row| {
-                row.get(0)
-            })
-            .unwrap_or(0);
-
-        for file in req.files {
-            let new_id = last_id + 1;
-            let indexs = new_id;
-
-            let states = serde_json::to_string(&FileAction::Upload).unwrap();
-            let content = file.content;
-            let paths = file.states.path;
-
-            meta_stmt.execute(params![
-                new_id,
-                file.states.name,
-                indexs,
-                paths,
+    /// return files the client has to fetch again,see `merge_upload`
+    fn store_file(&self, file: Pfile, device: &str) -> Result<Vec<MetaInner>> {
+        if !file.states.oldpath.is_empty()
+            && normalize_path(&file.states.oldpath) != file.states.key()
+        {
+            self.mark_moved(&file.states.oldpath)?;
+        }
+        let key = file.states.key();
+        let content = file.content.0;
+        let indexs = content_hash(&content);
+        // a note this device edited from an older version than the server has
+        if !device.is_empty() && key.ends_with(".md") {
+            let current = self.current_meta(&key)?;
+            let base = self.base_of(device, &key)?;
+            if let (Some(current), Some(base)) = (current, base) {
+                if current.indexs != indexs && current.indexs != base {
+                    return self.merge_upload(&file.states, content, &current, &base, device);
+                }
+            }
+        }
+        self.put_content(&indexs, &content)?;
+        self.upsert_meta(&file.states, &indexs)?;
+        self.set_base(device, &key, &indexs)?;
+        Ok(vec![])
+    }
+    /// Three-way merge of an upload against the version the device last had.
+    ///
+    /// A clean merge is stored and sent back for download.Otherwise the server version
+    /// stays and the upload is kept as a sibling conflicted copy.
+    fn merge_upload(
+        &self,
+        fileinfo: &FileInfo,
+        content: Vec<u8>,
+        current: &Meta,
+        base: &str,
+        device: &str,
+    ) -> Result<Vec<MetaInner>> {
+        let key = fileinfo.key();
+        let indexs = content_hash(&content);
+        let base = String::from_utf8(self.get_content(base)?);
+        let ours = String::from_utf8(self.get_content(&current.indexs)?);
+        let merged = match (base, ours, std::str::from_utf8(&content)) {
+            (Ok(base), Ok(ours), Ok(theirs)) => merge3(&base, &ours, theirs),
+            _ => None,
+        };
+        match merged {
+            Some(text) => {
+                let bytes = text.into_bytes();
+                let hash = content_hash(&bytes);
+                let info = FileInfo {
+                    mtime: fileinfo.mtime.max(current.mtime),
+                    hash: hash.clone(),
+                    ..fileinfo.clone()
+                };
+                self.put_content(&hash, &bytes)?;
+                self.upsert_meta(&info, &hash)?;
+                // the device still holds what it sent until it downloads the merge
+                self.put_content(&indexs, &content)?;
+                self.set_base(device, &key, &indexs)?;
+                log::info!("merged concurrent edits of {key} from device {device}");
+                Ok(vec![MetaInner::from_fileinfo(FileAction::Download, &info)])
+            }
+            None => {
+                let copy = conflicted_copy(fileinfo, device, &indexs);
+                self.put_content(&indexs, &content)?;
+                self.upsert_meta(&copy, &indexs)?;
+                // the device still holds its own edit,its base stays until it downloads
+                // the server version
+                log::info!("conflicting edits of {key},upload kept as {}", copy.path);
+                Ok(vec![
+                    MetaInner::new(FileAction::Conflict, current),
+                    MetaInner::from_fileinfo(FileAction::Download, &copy),
+                ])
+            }
+        }
+    }
+    fn session_key(&self, device: &str) -> Result<Option<String>> {
+        self.conn
+            .query_row(
+                "SELECT skey FROM chunk_sessions WHERE device = ?",
+                params![device],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+    fn open_session(&self, device: &str, skey: &str) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        match self.session_key(device)? {
+            Some(s) if s == skey => {}
+            Some(s) => {
+                log::warn!("device {device} drops stale session {s}");
+                self.drop_session(device)?;
+            }
+            None => {}
+        }
+        tx.execute(
+            "INSERT INTO chunk_sessions (device, skey, last_used) VALUES (?, ?, ?)
+             ON CONFLICT (device) DO UPDATE SET skey = excluded.skey, last_used = excluded.last_used",
+            params![device, skey, now_millis()],
+        )?;
+        tx.commit()
+    }
+    fn stage_file(&self, device: &str, file: &Pfile) -> Result<usize> {
+        let fileinfo = serde_json::to_string(&file.states)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        self.conn
+            .prepare_cached("INSERT INTO staged (device, fileinfo, content) VALUES (?, ?, ?)")?
+            .execute(params![device, fileinfo, file.content.0])
+    }
+    /// staged files are read one at a time,a session may hold more than fits in memory
+    fn finish_session(&self, device: &str, skey: &str) -> Result<Option<Vec<MetaInner>>> {
+        if self.session_key(device)?.as_deref() != Some(skey) {
+            return Ok(None);
+        }
+        let tx = self.conn.unchecked_transaction()?;
+        let ids = tx
+            .prepare("SELECT id FROM staged WHERE device = ? ORDER BY id")?
+            .query_map(params![device], |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<_>>>()?;
+        let mut merged = vec![];
+        for id in ids {
+            let (fileinfo, content) = tx.query_row(
+                "SELECT fileinfo, content FROM staged WHERE id = ?",
+                params![id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)),
+            )?;
+            let states = serde_json::from_str(&fileinfo).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e))
+            })?;
+            let file = Pfile {
                 states,
-                file.states.ctime as i64,
-                file.states.mtime as i64
-            ])?;
-            content_stmt.execute(params![indexs, content])?;
-
-            last_id = new_id;
+                content: FileContent(content),
+            };
+            merged.extend(self.store_file(file, device)?);
         }
+        self.drop_session(device)?;
+        tx.commit()?;
+        Ok(Some(merged))
+    }
+    fn drop_session(&self, device: &str) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM staged WHERE device = ?", params![device])?;
+        self.conn
+            .execute("DELETE FROM chunk_sessions WHERE device = ?", params![device])?;
         Ok(())
     }
-    pub(crate) fn update_meta_states(&self, meta_vec: &[MetaInner]) -> Result<(), rusqlite::Error> {
+    fn expire_sessions(&self, before: i64) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "DELETE FROM staged WHERE device IN
+             (SELECT device FROM chunk_sessions WHERE last_used < ?)",
+            params![before],
+        )?;
+        let n = tx.execute(
+            "DELETE FROM chunk_sessions WHERE last_used < ?",
+            params![before],
+        )?;
+        tx.commit()?;
+        Ok(n)
+    }
+    fn has_record(&self, key: &str) -> Result<bool> {
+        self.conn
+            .prepare_cached("SELECT 1 FROM meta WHERE pathkey = ?")?
+            .exists(params![key])
+    }
+    fn last_usn(&self) -> Result<i64> {
+        self.conn
+            .query_row("SELECT next - 1 FROM usn_counter", [], |row| row.get(0))
+    }
+    fn purged_usn(&self) -> Result<i64> {
+        self.conn
+            .query_row("SELECT usn FROM purged", [], |row| row.get(0))
+    }
+    /// records in the order they changed
+    fn changed_since(&self, usn: i64) -> Result<Vec<Meta>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {META_COLUMNS} FROM meta WHERE usn > ? ORDER BY usn"
+        ))?;
+        let rows = stmt.query_map(params![usn], to_meta)?;
+        rows.collect()
+    }
+    /// record at `key` unless it is deleted
+    fn current_meta(&self, key: &str) -> Result<Option<Meta>> {
+        self.conn
+            .query_row(
+                &format!("SELECT {META_COLUMNS} FROM meta WHERE pathkey = ? AND states != ?"),
+                params![key, state_of(&FileAction::Delete)],
+                to_meta,
+            )
+            .optional()
+    }
+    fn get_content(&self, hash: &str) -> Result<Vec<u8>> {
+        self.conn
+            .prepare_cached("SELECT content FROM content WHERE hash = ?")?
+            .query_row(params![hash], |row| row.get(0))
+    }
+    fn put_content(&self, hash: &str, content: &[u8]) -> Result<usize> {
+        // identical bytes are already there,nothing to write
+        self.conn
+            .prepare_cached("INSERT OR IGNORE INTO content (hash, content) VALUES (?, ?)")?
+            .execute(params![hash, content])
+    }
+    /// hash of the content `device` last got for `key`
+    fn base_of(&self, device: &str, key: &str) -> Result<Option<String>> {
+        self.conn
+            .query_row(
+                "SELECT hash FROM bases WHERE device = ? AND pathkey = ?",
+                params![device, key],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+    fn set_base(&self, device: &str, key: &str, hash: &str) -> Result<()> {
+        if device.is_empty() {
+            return Ok(());
+        }
+        self.conn
+            .prepare_cached(
+                "INSERT INTO bases (device, pathkey, hash) VALUES (?, ?, ?)
+                 ON CONFLICT (device, pathkey) DO UPDATE SET hash = excluded.hash",
+            )?
+            .execute(params![device, key, hash])?;
+        Ok(())
+    }
+    /// insert the record of a path,or update it if the path is already there.
+    /// Replaced content is kept as a revision.
+    fn upsert_meta(&self, fileinfo: &FileInfo, indexs: &str) -> Result<usize> {
+        self.conn
+            .prepare_cached(
+                "INSERT INTO revisions (pathkey, paths, indexs, ctime, mtime, created)
+                 SELECT pathkey, paths, indexs, ctime, mtime, ? FROM meta
+                 WHERE pathkey = ? AND indexs != ?",
+            )?
+            .execute(params![now_millis(), fileinfo.key(), indexs])?;
+        let mut stmt = self.conn.prepare_cached(
+            "INSERT INTO meta (fname, indexs, paths, states, ctime, mtime, pathkey)
+             VALUES (?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT (pathkey) DO UPDATE SET fname = excluded.fname, indexs = excluded.indexs,
+             paths = excluded.paths, states = excluded.states, ctime = excluded.ctime,
+             mtime = excluded.mtime",
+        )?;
+        stmt.execute(params![
+            fileinfo.name,
+            indexs,
+            fileinfo.path,
+            state_of(&FileAction::Upload),
+            fileinfo.ctime,
+            fileinfo.mtime,
+            fileinfo.key()
+        ])
+    }
+    /// Keep the old record of a moved file as a delete mark,so that other devices
+    /// remove the old path instead of downloading it again.
+    fn mark_moved(&self, oldpath: &str) -> Result<usize> {
+        self.conn.execute(
+            "UPDATE meta SET states = ? WHERE pathkey = ?",
+            params![state_of(&FileAction::Delete), normalize_path(oldpath)],
+        )
+    }
+    /// Repoint the record at `fileinfo.oldpath` to the new path,content is not
+    /// transferred again.
+    ///
+    /// Return false if nothing is found at the old path,or if the file may have been
+    /// edited as well (hash differs or is unknown).Then the new path keeps the mtime of
+    /// the old content until the client uploads its own.
+    fn rename_file(&self, fileinfo: &FileInfo) -> Result<bool> {
+        let oldkey = normalize_path(&fileinfo.oldpath);
+        if oldkey == fileinfo.key() {
+            return Ok(false);
+        }
+        let old = match self.current_meta(&oldkey)? {
+            Some(m) => m,
+            None => return Ok(false),
+        };
+        let unchanged = fileinfo.hash == old.indexs;
+        if unchanged {
+            self.upsert_meta(fileinfo, &old.indexs)?;
+        } else {
+            let moved = FileInfo {
+                mtime: old.mtime,
+                hash: old.hash(),
+                ..fileinfo.clone()
+            };
+            self.upsert_meta(&moved, &old.indexs)?;
+        }
+        self.mark_moved(&fileinfo.oldpath)?;
+        Ok(unchanged)
+    }
+    /// revisions of a file,newest first
+    fn revisions_of(&self, key: &str) -> Result<Vec<Revision>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, paths, indexs, ctime, mtime, created FROM revisions
+             WHERE pathkey = ? ORDER BY id DESC",
+        )?;
+        let rows = stmt.query_map(params![key], |row| {
+            Ok(Revision {
+                id: row.get(0)?,
+                path: row.get(1)?,
+                hash: row.get(2)?,
+                ctime: row.get(3)?,
+                mtime: row.get(4)?,
+                created: row.get(5)?,
+            })
+        })?;
+        rows.collect()
+    }
+    /// Make a revision the current content of its file,the replaced content
+    /// becomes a revision itself.Return None if there is no such revision.
+    fn restore_revision(&self, key: &str, id: i64) -> Result<Option<FileInfo>> {
+        let revision = self
+            .conn
+            .query_row(
+                "SELECT paths, indexs, ctime FROM revisions WHERE pathkey = ? AND id = ?",
+                params![key, id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        let (path, indexs, ctime) = match revision {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let fileinfo = FileInfo {
+            name: path.rsplit('/').next().unwrap_or_default().to_string(),
+            path,
+            // newer than any device has,so that all of them download it
+            mtime: now_millis(),
+            ctime,
+            oldpath: "".to_string(),
+            hash: indexs.clone(),
+        };
+        self.upsert_meta(&fileinfo, &indexs)?;
+        Ok(Some(fileinfo))
+    }
+    pub(crate) fn update_meta_states(
+        &self,
+        meta_vec: &[MetaInner],
+        device: &str,
+    ) -> Result<(), rusqlite::Error> {
         let conn = &self.conn;
 
         for meta in meta_vec {
             match meta.action {
                 FileAction::Delete => {
+                    // a repeated delete of the same file doesn't add another entry
+                    conn.execute(
+                        "INSERT INTO trash (pathkey, paths, indexs, ctime, mtime, deleted_by, deleted_at)
+                         SELECT pathkey, paths, indexs, ctime, mtime, ?, ? FROM meta
+                         WHERE pathkey = ? AND states != ?",
+                        params![
+                            device,
+                            now_millis(),
+                            meta.fileinfo.key(),
+                            state_of(&FileAction::Delete)
+                        ],
+                    )?;
                     conn.execute(
                         &format!(
-                            "UPDATE meta SET states = '{}' WHERE fname = ?",
+                            "UPDATE meta SET states = '{}' WHERE pathkey = ?",
                             serde_json::to_string(&FileAction::Delete).unwrap()
                         ),
-                        &[&meta.fileinfo.name],
+                        &[&meta.fileinfo.key()],
                     )?;
                 }
                 FileAction::Modify => {
                     conn.execute(
                         &format!(
-                            "UPDATE meta SET states = '{}' WHERE fname = ?",
+                            "UPDATE meta SET states = '{}' WHERE pathkey = ?",
                             serde_json::to_string(&FileAction::Modify).unwrap()
                         ),
-                        &[&meta.fileinfo.name],
+                        &[&meta.fileinfo.key()],
                     )?;
                 }
                 _ => unreachable!(),
@@ -182,10 +777,94 @@ impl Db {
 
         Ok(())
     }
+    /// files in trash,latest deletion first
+    fn trash_entries(&self) -> Result<Vec<TrashEntry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, paths, indexs, ctime, mtime, deleted_by, deleted_at FROM trash
+             ORDER BY deleted_at DESC",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(TrashEntry {
+                id: row.get(0)?,
+                path: row.get(1)?,
+                hash: row.get(2)?,
+                ctime: row.get(3)?,
+                mtime: row.get(4)?,
+                deleted_by: row.get(5)?,
+                deleted_at: row.get(6)?,
+            })
+        })?;
+        rows.collect()
+    }
+    /// Bring back the latest deleted version of a file,with mtime now so that
+    /// every device downloads it.Return None if it is not in trash,or if a file
+    /// was created at its path after the delete,that one is not replaced.
+    fn undelete(&self, key: &str) -> Result<Option<FileInfo>> {
+        if self.current_meta(key)?.is_some() {
+            log::warn!("undelete of {key} skipped,it exists again");
+            return Ok(None);
+        }
+        let entry = self
+            .conn
+            .query_row(
+                "SELECT paths, indexs, ctime FROM trash WHERE pathkey = ?
+                 ORDER BY deleted_at DESC, id DESC LIMIT 1",
+                params![key],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        let (path, indexs, ctime) = match entry {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        let fileinfo = FileInfo {
+            name: path.rsplit('/').next().unwrap_or_default().to_string(),
+            path,
+            mtime: now_millis(),
+            ctime,
+            oldpath: "".to_string(),
+            hash: indexs.clone(),
+        };
+        self.upsert_meta(&fileinfo, &indexs)?;
+        self.conn.execute("DELETE FROM trash WHERE pathkey = ?", params![key])?;
+        Ok(Some(fileinfo))
+    }
+    /// Drop trash entries deleted before `before`,together with the delete marks,
+    /// revisions and merge bases of files that stay deleted.
+    fn purge_trash(&self, before: i64) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        let purged = "FROM meta WHERE states = ?1
+             AND pathkey IN (SELECT pathkey FROM trash WHERE deleted_at < ?2)
+             AND pathkey NOT IN (SELECT pathkey FROM trash WHERE deleted_at >= ?2)";
+        // clients that haven't seen these delete marks yet can't get them from a cursor
+        tx.execute(
+            &format!("UPDATE purged SET usn = max(usn, (SELECT COALESCE(max(usn), 0) {purged}))"),
+            params![state_of(&FileAction::Delete), before],
+        )?;
+        tx.execute(
+            &format!("DELETE {purged}"),
+            params![state_of(&FileAction::Delete), before],
+        )?;
+        let n = tx.execute("DELETE FROM trash WHERE deleted_at < ?", params![before])?;
+        tx.execute_batch(
+            "DELETE FROM revisions WHERE pathkey NOT IN (SELECT pathkey FROM meta);
+             DELETE FROM bases WHERE pathkey NOT IN (SELECT pathkey FROM meta);",
+        )?;
+        tx.commit()?;
+        self.collect_garbage()?;
+        Ok(n)
+    }
+    /// delete unreferenced blobs,return the number of them
+    pub(crate) fn collect_garbage(&self) -> Result<usize> {
+        self.conn.execute(
+            &format!("DELETE FROM content WHERE hash IN ({UNREFERENCED_BLOBS})"),
+            [],
+        )
+    }
     fn get_meta_records(&self) -> Result<Option<Vec<Meta>>> {
         let mut stmt = self
             .conn
-            .prepare("SELECT id, fname, indexs,paths, states, ctime, mtime FROM meta")?;
+            .prepare(&format!("SELECT {META_COLUMNS} FROM meta"))?;
         let rows = stmt.query_map([], to_meta)?;
 
         let mut meta_records = Vec::new();
@@ -202,20 +881,295 @@ impl Db {
     }
 }
 
-/// return username and hash of each user
-pub(crate) fn fetch_users(auth_db: &str) -> Result<Option<Vec<(String, String)>>, rusqlite::Error> {
-    let sql = "SELECT username,hash FROM auth";
-    let conn = Connection::open(auth_db)?;
-    let mut stmt = conn.prepare(sql)?;
-    // [Ok(TB { c: "c1", idx: 1 }), Ok(TB { c: "c2", idx: 2 })]
-    let r = stmt
-        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
-        .filter_map(|e| e.ok())
-        .collect::<Vec<_>>();
-    Ok(if r.is_empty() { None } else { Some(r) })
-}
 #[test]
 fn test_to_from_str() {
     let s = serde_json::to_string(&FileAction::Delete).unwrap();
     assert_eq!("\"delete\"", s)
 }
+
+#[cfg(test)]
+fn pfile(path: &str, content: &str) -> Pfile {
+    Pfile {
+        states: FileInfo {
+            name: path.to_string(),
+            path: path.to_string(),
+            mtime: 1,
+            ..Default::default()
+        },
+        content: FileContent(content.as_bytes().to_vec()),
+    }
+}
+
+#[test]
+fn chunk_session_commit_and_abort() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = DbManager::new(dir.path()).unwrap();
+    db.open_session("pc", "s1").unwrap();
+    db.stage("pc", &pfile("a.md", "a")).unwrap();
+    assert!(!db.has_file("a.md").unwrap());
+    assert!(db.finish_session("pc", "s2").unwrap().is_none());
+    assert!(db.finish_session("pc", "s1").unwrap().is_some());
+    assert!(db.has_file("a.md").unwrap());
+    // finished sessions are closed
+    assert!(db.finish_session("pc", "s1").unwrap().is_none());
+
+    db.open_session("pc", "s3").unwrap();
+    db.stage("pc", &pfile("b.md", "b")).unwrap();
+    db.abort_session("pc", "s3").unwrap();
+    assert!(db.finish_session("pc", "s3").unwrap().is_none());
+    assert!(!db.has_file("b.md").unwrap());
+}
+
+#[test]
+fn concurrent_chunk_sessions() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = DbManager::new(dir.path()).unwrap();
+    db.open_session("pc", "sA").unwrap();
+    db.stage("pc", &pfile("a.md", "a")).unwrap();
+    // another device uploads and opens a session of its own meanwhile
+    db.upload(pfile("b.md", "b"), "phone").unwrap();
+    db.open_session("phone", "sB").unwrap();
+    db.stage("phone", &pfile("c.md", "c")).unwrap();
+    db.abort_session("pc", "sA").unwrap();
+    assert!(db.has_file("b.md").unwrap());
+    assert!(!db.has_file("a.md").unwrap());
+    db.finish_session("phone", "sB").unwrap().unwrap();
+    assert!(db.has_file("c.md").unwrap());
+    // a new key of the same device drops the stale session
+    db.open_session("pc", "s1").unwrap();
+    db.stage("pc", &pfile("d.md", "d")).unwrap();
+    db.open_session("pc", "s2").unwrap();
+    db.finish_session("pc", "s2").unwrap().unwrap();
+    assert!(!db.has_file("d.md").unwrap());
+}
+
+#[test]
+fn idle_chunk_sessions_expire() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = DbManager::new(dir.path()).unwrap();
+    db.open_session("pc", "s1").unwrap();
+    db.stage("pc", &pfile("a.md", "a")).unwrap();
+    assert_eq!(db.expire_sessions().unwrap(), 0);
+    assert_eq!(db.db.expire_sessions(now_millis() + 1).unwrap(), 1);
+    assert!(db.finish_session("pc", "s1").unwrap().is_none());
+    let staged: i64 = db
+        .db
+        .conn
+        .query_row("SELECT count(*) FROM staged", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(staged, 0);
+}
+
+/// db as written by the first release,before `user_version` was set
+#[cfg(test)]
+fn baseline_db(folder: &Path) -> Connection {
+    let conn = Connection::open(folder.join("obsidian.db")).unwrap();
+    conn.execute_batch(
+        "CREATE TABLE meta (id INTEGER PRIMARY KEY, fname TEXT NOT NULL, indexs INTEGER NOT NULL,
+         paths TEXT NOT NULL, states TEXT NOT NULL, ctime INTEGER NOT NULL, mtime INTEGER NOT NULL);
+         CREATE TABLE content (id INTEGER PRIMARY KEY, content TEXT NOT NULL);
+         INSERT INTO content VALUES (1, 'old'), (2, 'new'), (3, 'daily');
+         INSERT INTO meta VALUES (1, 'a.md', 1, 'a.md', '\"upload\"', 1, 10);
+         INSERT INTO meta VALUES (2, 'a.md', 2, 'a.md', '\"upload\"', 1, 20);
+         INSERT INTO meta VALUES (3, 'n.md', 3, 'Daily\\n.md', '\"upload\"', 1, 20);",
+    )
+    .unwrap();
+    conn
+}
+
+#[test]
+fn upgrade_baseline_db() {
+    let dir = tempfile::tempdir().unwrap();
+    drop(baseline_db(dir.path()));
+    let db = DbManager::new(dir.path()).unwrap();
+    let version: u32 = db
+        .db
+        .conn
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .unwrap();
+    assert_eq!(version, SCHEMA_VERSION);
+    let files = db
+        .download(
+            DownloadRequest {
+                paths: vec!["a.md".to_string(), "Daily/n.md".to_string()],
+            },
+            "",
+        )
+        .unwrap()
+        .files;
+    let contents = files.iter().map(|f| f.content.0.as_slice()).collect::<Vec<_>>();
+    assert_eq!(contents, [b"new".as_slice(), b"daily".as_slice()]);
+    drop(db);
+    // opening it again changes nothing
+    DbManager::new(dir.path()).unwrap();
+}
+
+#[test]
+fn upgrade_files_without_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let conn = baseline_db(dir.path());
+    conn.execute_batch(
+        "INSERT INTO content VALUES (4, 'b'), (5, 'c');
+         INSERT INTO meta VALUES (4, 'b.md', 4, '', '\"upload\"', 1, 30);
+         INSERT INTO meta VALUES (5, 'c.md', 5, '', '\"upload\"', 1, 30);",
+    )
+    .unwrap();
+    drop(conn);
+    let db = DbManager::new(dir.path()).unwrap();
+    for key in ["a.md", "b.md", "c.md", "Daily/n.md"] {
+        assert!(db.has_file(key).unwrap(), "{key}");
+    }
+}
+
+#[test]
+fn upgrade_pathkey_of_files_without_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let conn = baseline_db(dir.path());
+    upgrade_step(&conn, 1, |c| c.execute_batch(include_str!("upgrades/v1.sql"))).unwrap();
+    upgrade_step(&conn, 2, upgrade_v2).unwrap();
+    upgrade_step(&conn, 3, |c| c.execute_batch(include_str!("upgrades/v3.sql"))).unwrap();
+    // uploaded by a client that only sent a name
+    let hash = content_hash(b"e");
+    conn.execute(
+        "INSERT INTO content (hash, content) VALUES (?, ?)",
+        params![hash, b"e".to_vec()],
+    )
+    .unwrap();
+    conn.execute(
+        "INSERT INTO meta (fname, indexs, paths, states, ctime, mtime)
+         VALUES ('e.md', ?, '', '\"upload\"', 1, 1)",
+        params![hash],
+    )
+    .unwrap();
+    drop(conn);
+    let db = DbManager::new(dir.path()).unwrap();
+    assert!(db.has_file("e.md").unwrap());
+    assert!(db.has_file("Daily/n.md").unwrap());
+}
+
+#[test]
+fn resume_interrupted_upgrade() {
+    let dir = tempfile::tempdir().unwrap();
+    let conn = baseline_db(dir.path());
+    // the process died after the first step
+    upgrade_step(&conn, 1, |c| c.execute_batch(include_str!("upgrades/v1.sql"))).unwrap();
+    drop(conn);
+    let db = DbManager::new(dir.path()).unwrap();
+    assert!(db.has_file("a.md").unwrap());
+    assert!(db.has_file("Daily/n.md").unwrap());
+}
+
+#[test]
+fn usn_counts_real_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = DbManager::new(dir.path()).unwrap();
+    assert_eq!(db.cursor().unwrap(), 0);
+    db.upload(pfile("a.md", "a"), "pc").unwrap();
+    db.upload(pfile("b.md", "b"), "pc").unwrap();
+    assert_eq!(db.cursor().unwrap(), 2);
+    // the same upload again changes nothing
+    db.upload(pfile("a.md", "a"), "pc").unwrap();
+    assert_eq!(db.cursor().unwrap(), 2);
+    db.upload(pfile("a.md", "a2"), "pc").unwrap();
+    assert_eq!(db.cursor().unwrap(), 3);
+    let changed = db.db.changed_since(2).unwrap();
+    assert_eq!(changed.iter().map(|m| m.key()).collect::<Vec<_>>(), ["a.md"]);
+    // the other device hasn't got a.md
+    let changes = db.changes_since(0, "phone").unwrap();
+    assert_eq!(changes.len(), 2);
+    assert!(db.changes_since(0, "pc").unwrap().is_empty());
+}
+
+#[test]
+fn purged_delete_marks_expire_older_cursors() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = DbManager::new(dir.path()).unwrap();
+    db.upload(pfile("a.md", "a"), "pc").unwrap();
+    db.upload(pfile("b.md", "b"), "pc").unwrap();
+    let delete = MetaInner::from_fileinfo(FileAction::Delete, &pfile("a.md", "").states);
+    db.update_stetes(&[delete], "pc").unwrap();
+    let cursor = db.cursor().unwrap();
+    assert!(!db.cursor_expired(0).unwrap());
+    assert_eq!(db.db.purge_trash(now_millis() + 1).unwrap(), 1);
+    assert!(!db.has_file("a.md").unwrap());
+    assert!(db.cursor_expired(cursor - 1).unwrap());
+    assert!(!db.cursor_expired(cursor).unwrap());
+    assert!(db.cursor_expired(cursor + 1).unwrap());
+}
+
+#[test]
+fn concurrent_note_edits_are_merged() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = DbManager::new(dir.path()).unwrap();
+    let content = |db: &DbManager| {
+        let req = DownloadRequest {
+            paths: vec!["a.md".to_string()],
+        };
+        let files = db.download(req, "").unwrap().files;
+        String::from_utf8(files[0].content.0.clone()).unwrap()
+    };
+    db.upload(pfile("a.md", "a\nb\nc\n"), "pc").unwrap();
+    db.upload(pfile("a.md", "a\nb\nc\n"), "phone").unwrap();
+    db.upload(pfile("a.md", "a1\nb\nc\n"), "phone").unwrap();
+    // pc edited the version before the phone's edit
+    let files = db.upload(pfile("a.md", "a\nb\nc\nd\n"), "pc").unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].action, FileAction::Download);
+    assert_eq!(content(&db), "a1\nb\nc\nd\n");
+}
+
+#[test]
+fn conflicting_note_edits_keep_a_copy() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = DbManager::new(dir.path()).unwrap();
+    db.upload(pfile("a.md", "a\nb\n"), "pc").unwrap();
+    db.upload(pfile("a.md", "a\nb\n"), "phone").unwrap();
+    db.upload(pfile("a.md", "a1\nb\n"), "phone").unwrap();
+    let server = db.current("a.md").unwrap().unwrap().hash();
+    let files = db.upload(pfile("a.md", "a2\nb\n"), "pc").unwrap();
+    let actions = files.iter().map(|e| e.action.clone()).collect::<Vec<_>>();
+    assert_eq!(actions, [FileAction::Conflict, FileAction::Download]);
+    let copy = files[1].fileinfo.key();
+    assert!(copy.starts_with("a (conflicted copy pc "), "{copy}");
+    assert_eq!(db.current(&copy).unwrap().unwrap().hash(), content_hash(b"a2\nb\n"));
+    // sent again before pc downloaded the server version,the phone's edit stays
+    let files = db.upload(pfile("a.md", "a2\nb\n"), "pc").unwrap();
+    assert_eq!(files[0].action, FileAction::Conflict);
+    assert_eq!(db.current("a.md").unwrap().unwrap().hash(), server);
+}
+
+#[test]
+fn trash_and_undelete() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = DbManager::new(dir.path()).unwrap();
+    let delete = |path: &str| {
+        let delete = MetaInner::from_fileinfo(FileAction::Delete, &pfile(path, "").states);
+        db.update_stetes(&[delete], "phone").unwrap();
+    };
+    let undelete = |path: &str| {
+        let req = UndeleteRequest {
+            paths: vec![path.to_string()],
+        };
+        db.undelete(req).unwrap()
+    };
+    db.upload(pfile("a.md", "a"), "pc").unwrap();
+    db.upload(pfile("b.md", "b"), "pc").unwrap();
+    delete("a.md");
+    delete("b.md");
+    let trash = db.trash().unwrap().files;
+    assert_eq!(trash.len(), 2);
+    assert!(trash.iter().all(|e| e.deleted_by == "phone"));
+    assert!(db.current("a.md").unwrap().is_none());
+
+    let restored = undelete("a.md");
+    assert_eq!(restored.len(), 1);
+    assert_eq!(restored[0].hash, content_hash(b"a"));
+    assert_eq!(db.current("a.md").unwrap().unwrap().hash(), content_hash(b"a"));
+    assert_eq!(db.trash().unwrap().files.len(), 1);
+    // not in trash any more
+    assert!(undelete("a.md").is_empty());
+    // created again after the delete,the new file stays
+    db.upload(pfile("b.md", "b2"), "pc").unwrap();
+    assert!(undelete("b.md").is_empty());
+    assert_eq!(db.current("b.md").unwrap().unwrap().hash(), content_hash(b"b2"));
+}
@@ -1,13 +1,15 @@
-use actix_web::{HttpResponse, ResponseError};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use rusqlite::ErrorCode;
+use serde::Serialize;
 use thiserror::Error;
+
+use crate::user::UserError;
 #[derive(Error, Debug)]
 pub enum ApplicationError {
     #[error("Sqlite error: {0}")]
     Sqlite(#[from] rusqlite::Error),
     #[error("IO error: {0}")]
     IO(#[from] std::io::Error),
-    #[error("Actix web error: {0}")]
-    Actix(#[from] actix_web::Error),
     #[error(transparent)]
     UserError(#[from] crate::user::UserError),
     #[error("Error while serializing data: {0}")]
@@ -18,28 +20,132 @@ pub enum ApplicationError {
     LaunchServer(String),
     #[error("Json parsing error: {0}")]
     JsonParsing(#[from] serde_json::Error),
+    #[error("Invalid sync header: {0}")]
+    InvalidHeader(String),
+    #[error("Unsupported protocol version: {0}")]
+    UnsupportedVersion(String),
     #[error("Error while get host key: {0}")]
     InvalidHostKey(String),
+    #[error("Invalid sync session: {0}")]
+    InvalidSession(String),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    /// the client has to do a full meta
+    #[error("Cursor expired: {0}")]
+    CursorExpired(String),
+    /// seconds until the lockout is over
+    #[error("Too many failed logins,retry after {0}s")]
+    TooManyRequests(u64),
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error("Blocking task error: {0}")]
+    Blocking(#[from] actix_web::error::BlockingError),
+}
+
+/// Body of every error response.`code` stays the same across versions,
+/// clients match on it rather than on `message`.
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    /// whether the same request may succeed later
+    pub retryable: bool,
+}
+
+impl ApplicationError {
+    /// status,code and retryable flag sent to the client
+    fn kind(&self) -> (StatusCode, &'static str, bool) {
+        match self {
+            ApplicationError::UserError(UserError::Authentication(_)) => {
+                (StatusCode::FORBIDDEN, "auth_failed", false)
+            }
+            ApplicationError::UserError(UserError::MissingValues(_)) => {
+                (StatusCode::BAD_REQUEST, "bad_request", false)
+            }
+            ApplicationError::UserError(UserError::Sqlite(e)) | ApplicationError::Sqlite(e) => {
+                sqlite_kind(e)
+            }
+            ApplicationError::UserError(UserError::IO(e)) | ApplicationError::IO(e) => io_kind(e),
+            ApplicationError::InvalidHostKey(_) => {
+                (StatusCode::UNAUTHORIZED, "invalid_host_key", false)
+            }
+            ApplicationError::JsonParsing(_) => (StatusCode::BAD_REQUEST, "malformed_json", false),
+            ApplicationError::InvalidHeader(_) => {
+                (StatusCode::BAD_REQUEST, "invalid_header", false)
+            }
+            ApplicationError::UnsupportedVersion(_) => {
+                (StatusCode::UPGRADE_REQUIRED, "unsupported_version", false)
+            }
+            ApplicationError::InvalidSession(_) => {
+                (StatusCode::BAD_REQUEST, "invalid_session", false)
+            }
+            ApplicationError::NotFound(_) => (StatusCode::NOT_FOUND, "not_found", false),
+            ApplicationError::CursorExpired(_) => (StatusCode::GONE, "cursor_expired", false),
+            ApplicationError::TooManyRequests(_) => {
+                (StatusCode::TOO_MANY_REQUESTS, "too_many_requests", true)
+            }
+            ApplicationError::PayloadTooLarge(_) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", false)
+            }
+            ApplicationError::Blocking(_) => (StatusCode::SERVICE_UNAVAILABLE, "unavailable", true),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "internal", false),
+        }
+    }
+}
+
+fn sqlite_kind(e: &rusqlite::Error) -> (StatusCode, &'static str, bool) {
+    match e.sqlite_error_code() {
+        Some(ErrorCode::DiskFull) => (StatusCode::INSUFFICIENT_STORAGE, "storage_full", true),
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => {
+            (StatusCode::SERVICE_UNAVAILABLE, "busy", true)
+        }
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "internal", false),
+    }
+}
+
+/// ENOSPC on linux and macos
+const NO_SPACE: i32 = 28;
+
+fn io_kind(e: &std::io::Error) -> (StatusCode, &'static str, bool) {
+    if e.raw_os_error() == Some(NO_SPACE) {
+        (StatusCode::INSUFFICIENT_STORAGE, "storage_full", true)
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, "internal", false)
+    }
 }
 
 /// Actix Web uses `ResponseError` for conversion of errors to a response
 impl ResponseError for ApplicationError {
+    fn status_code(&self) -> StatusCode {
+        self.kind().0
+    }
+
     fn error_response(&self) -> HttpResponse {
-        match self {
-            ApplicationError::UserError(e) => {
-                // found in anki/rslib/src/error/network.rs
-                log::error!("{}", e.to_string());
-                HttpResponse::Forbidden().finish()
-            }
-            // ApplicationError::InvalidHostKey(e) => {
-            //     // found in anki/rslib/src/error/network.rs
-            //     log::error!("{}", e.to_string());
-            //     HttpResponse::Forbidden().finish()
-            // }
-            e => {
-                log::error!("{}", e.to_string());
-                HttpResponse::InternalServerError().finish()
-            }
+        let (status, code, retryable) = self.kind();
+        // too many requests is logged where the login is refused
+        if !matches!(self, ApplicationError::TooManyRequests(_)) {
+            log::error!("{self}");
+        }
+        let mut resp = HttpResponse::build(status);
+        if let ApplicationError::TooManyRequests(secs) = self {
+            resp.insert_header(("Retry-After", secs.to_string()));
         }
+        resp.json(ErrorBody {
+            code,
+            message: self.to_string(),
+            retryable,
+        })
     }
 }
+
+#[test]
+fn error_code_and_status() {
+    let e = ApplicationError::InvalidHostKey("expired".to_string());
+    assert_eq!(e.status_code(), StatusCode::UNAUTHORIZED);
+    let e: ApplicationError = serde_json::from_str::<u8>("{").unwrap_err().into();
+    assert_eq!(e.kind(), (StatusCode::BAD_REQUEST, "malformed_json", false));
+    let e: ApplicationError = std::io::Error::from_raw_os_error(NO_SPACE).into();
+    assert_eq!(e.kind(), (StatusCode::INSUFFICIENT_STORAGE, "storage_full", true));
+}
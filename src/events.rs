@@ -0,0 +1,172 @@
+// Push changes to connected devices with server-sent events,so that an edit on one device
+// reaches the others without waiting for their next meta.
+
+use std::{future::Future, sync::Mutex, time::Duration};
+
+use actix_web::{http::header::ContentEncoding, rt, web, HttpResponse};
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+use futures_util::stream;
+
+use crate::protocol::ChangeEvent;
+
+/// a comment line every so often keeps proxies from closing an idle stream
+#[cfg(not(test))]
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// short enough for tests to see keep-alives and revoked sessions
+#[cfg(test)]
+const KEEP_ALIVE: Duration = Duration::from_millis(300);
+/// events a slow device may lag behind,later ones are dropped for it
+const BACKLOG: usize = 16;
+
+/// Devices of one user listening for changes.
+#[derive(Default)]
+pub(crate) struct Notifier {
+    subscribers: Mutex<Vec<Sender<String>>>,
+}
+
+impl Notifier {
+    pub(crate) fn subscribe(&self) -> Receiver<String> {
+        let (tx, rx) = channel::bounded(BACKLOG);
+        self.subscribers.lock().expect("mutex lock").push(tx);
+        rx
+    }
+    /// Send `event` to every device still connected.
+    pub(crate) fn notify(&self, event: &ChangeEvent) {
+        let data = match serde_json::to_string(event) {
+            Ok(d) => d,
+            Err(e) => {
+                log::error!("{e}");
+                return;
+            }
+        };
+        // a full channel only misses this event,the next meta catches up anyway
+        self.subscribers
+            .lock()
+            .expect("mutex lock")
+            .retain(|tx| !matches!(tx.try_send(data.clone()), Err(TrySendError::Closed(_))));
+    }
+}
+
+/// Stream events of `rx` as `text/event-stream`.
+///
+/// `alive` is asked on every keep-alive,the stream ends once the session it was opened
+/// with is revoked or expired.
+pub(crate) fn sse<F, Fut>(rx: Receiver<String>, alive: F) -> HttpResponse
+where
+    F: Fn() -> Fut + 'static,
+    Fut: Future<Output = bool>,
+{
+    let events = stream::unfold((rx, alive), |(rx, alive)| async move {
+        let chunk = match rt::time::timeout(KEEP_ALIVE, rx.recv()).await {
+            Ok(Ok(data)) => format!("event: change\ndata: {data}\n\n"),
+            // notifier is gone with its user
+            Ok(Err(_)) => return None,
+            Err(_) if !alive().await => return None,
+            Err(_) => ": keep-alive\n\n".to_string(),
+        };
+        Some((Ok::<_, actix_web::Error>(web::Bytes::from(chunk)), (rx, alive)))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        // compressing would hold events back in the encoder
+        .insert_header(ContentEncoding::Identity)
+        .streaming(events)
+}
+
+/// next chunk of a streamed body,None once it ended
+#[cfg(test)]
+async fn next_chunk<B: actix_web::body::MessageBody + Unpin>(body: &mut B) -> Option<String> {
+    let chunk = futures_util::future::poll_fn(|cx| std::pin::Pin::new(&mut *body).poll_next(cx))
+        .await?;
+    Some(String::from_utf8_lossy(&chunk.ok()?).to_string())
+}
+
+#[actix_web::test]
+async fn committed_changes_are_pushed() {
+    use actix_web::{test, App};
+
+    use crate::{
+        config::{ConfigLimits, ConfigLogin},
+        handler::{events_handler, sync_handler},
+        protocol::Server,
+        ratelimit::LoginLimiter,
+        request::{SyncRequestWrapper, SYNC_HEADER_NAME},
+        user::{add_user, create_auth_db, revoke_session, session_list},
+    };
+
+    const FILEINFO: &str = r#"{"name":"a.md","path":"a.md","mtime":10,"ctime":1,"oldpath":""}"#;
+
+    let dir = tempfile::tempdir().unwrap();
+    let auth_db = dir.path().join("auth.db");
+    create_auth_db(&auth_db).unwrap();
+    add_user(&["bob".to_string(), "pw".to_string()], &auth_db).unwrap();
+    let limiter = LoginLimiter::new(&ConfigLogin::default());
+    let server = Server::new_from_db(
+        &dir.path().join("collections"),
+        auth_db.to_str().unwrap(),
+        0,
+        limiter,
+    )
+    .unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(server))
+            .service(web::resource("/events").route(web::get().to(events_handler)))
+            .service(web::resource("/{mehod}").to(sync_handler))
+            .wrap(SyncRequestWrapper::new(ConfigLimits::default())),
+    )
+    .await;
+    let mut tokens = vec![];
+    for device in ["pc", "phone"] {
+        let req = test::TestRequest::post()
+            .uri("/hostKey")
+            .set_payload(format!(
+                r#"{{"username":"bob","password":"pw","device":"{device}"}}"#
+            ))
+            .to_request();
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        tokens.push(resp["key"].as_str().unwrap().to_string());
+    }
+    let header = |token: &str| format!(r#"{{"k":"{token}","s":"s1","d":"phone"}}"#);
+    let req = test::TestRequest::get()
+        .uri("/events")
+        .insert_header((SYNC_HEADER_NAME.clone(), header(&tokens[0])))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    let mut events = resp.into_body();
+
+    // changes of the phone,each answered before the next one is sent
+    let phone = |method: &str, body: String| {
+        test::TestRequest::post()
+            .uri(&format!("/{method}"))
+            .insert_header((SYNC_HEADER_NAME.clone(), header(&tokens[1])))
+            .set_payload(body)
+            .to_request()
+    };
+    let upload = format!(r#"{{"files":[{{"states":{FILEINFO},"content":"hi"}}]}}"#);
+    let resp = test::call_service(&app, phone("upload", upload.clone())).await;
+    assert!(resp.status().is_success());
+    let change = |cursor: i64| {
+        format!("event: change\ndata: {{\"cursor\":{cursor},\"device\":\"phone\"}}\n\n")
+    };
+    assert_eq!(next_chunk(&mut events).await.unwrap(), change(1));
+    let delete = format!(r#"{{"states":[{{"action":"delete","fileinfo":{FILEINFO}}}]}}"#);
+    let resp = test::call_service(&app, phone("meta", delete)).await;
+    assert!(resp.status().is_success());
+    assert_eq!(next_chunk(&mut events).await.unwrap(), change(2));
+    // staged files of an open session are no change yet
+    let resp = test::call_service(&app, phone("applyChunk", upload)).await;
+    assert!(resp.status().is_success());
+    assert_eq!(next_chunk(&mut events).await.unwrap(), ": keep-alive\n\n");
+    let resp = test::call_service(&app, phone("finish", String::new())).await;
+    assert!(resp.status().is_success());
+    assert_eq!(next_chunk(&mut events).await.unwrap(), change(3));
+
+    // the stream ends at the next keep-alive once the pc's session is revoked
+    let sessions = session_list("bob", &auth_db).unwrap();
+    let pc = sessions.iter().find(|s| s.device == "pc").unwrap();
+    assert!(revoke_session(pc.id, &auth_db).unwrap());
+    assert!(next_chunk(&mut events).await.is_none());
+}
@@ -1,14 +1,96 @@
 CREATE TABLE IF NOT EXISTS meta (
     id INTEGER PRIMARY KEY ,
     fname TEXT NOT NULL,
-    -- index to content
-    indexs INTEGER NOT NULL,
+    -- index to content,the sha256 of file content
+    indexs TEXT NOT NULL,
     paths TEXT NOT NULL,
     states TEXT NOT NULL,
     ctime INTEGER NOT NULL,
-    mtime INTEGER NOT NULL
+    mtime INTEGER NOT NULL,
+    -- normalized paths,the key of a file
+    pathkey TEXT NOT NULL,
+    -- change sequence number,set by the triggers below
+    usn INTEGER NOT NULL DEFAULT 0
 );
+-- content is stored once under its sha256,whatever number of files share it
 CREATE TABLE IF NOT EXISTS content (
+    hash TEXT PRIMARY KEY,
+    content BLOB NOT NULL
+);
+-- one record per file,uploads of an existing path update it
+CREATE UNIQUE INDEX IF NOT EXISTS meta_pathkey ON meta (pathkey);
+-- every change of a record gets the next usn,clients ask for changes after the last one they saw
+CREATE INDEX IF NOT EXISTS meta_usn ON meta (usn);
+CREATE TABLE IF NOT EXISTS usn_counter (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    next INTEGER NOT NULL
+);
+INSERT OR IGNORE INTO usn_counter (id, next) VALUES (0, 1);
+-- highest usn of the delete marks purged from trash,an older cursor may have missed a delete
+CREATE TABLE IF NOT EXISTS purged (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    usn INTEGER NOT NULL
+);
+INSERT OR IGNORE INTO purged (id, usn) VALUES (0, 0);
+CREATE TRIGGER IF NOT EXISTS meta_usn_insert AFTER INSERT ON meta
+BEGIN
+    UPDATE meta SET usn = (SELECT next FROM usn_counter) WHERE id = NEW.id;
+    UPDATE usn_counter SET next = next + 1;
+END;
+CREATE TRIGGER IF NOT EXISTS meta_usn_update AFTER UPDATE OF fname, indexs, paths, states, ctime, mtime, pathkey ON meta
+WHEN OLD.fname IS NOT NEW.fname OR OLD.indexs IS NOT NEW.indexs OR OLD.paths IS NOT NEW.paths
+    OR OLD.states IS NOT NEW.states OR OLD.ctime IS NOT NEW.ctime OR OLD.mtime IS NOT NEW.mtime
+    OR OLD.pathkey IS NOT NEW.pathkey
+BEGIN
+    UPDATE meta SET usn = (SELECT next FROM usn_counter) WHERE id = NEW.id;
+    UPDATE usn_counter SET next = next + 1;
+END;
+-- content each device last got of a file,the base of a three-way merge
+CREATE TABLE IF NOT EXISTS bases (
+    device TEXT NOT NULL,
+    pathkey TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    PRIMARY KEY (device, pathkey)
+);
+-- previous versions of a file,a new one is added whenever its content changes
+CREATE TABLE IF NOT EXISTS revisions (
+    id INTEGER PRIMARY KEY,
+    pathkey TEXT NOT NULL,
+    paths TEXT NOT NULL,
+    indexs TEXT NOT NULL,
+    ctime INTEGER NOT NULL,
+    mtime INTEGER NOT NULL,
+    -- when it was replaced,in milliseconds
+    created INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS revisions_pathkey ON revisions (pathkey);
+-- deleted files,kept for the configured number of days
+CREATE TABLE IF NOT EXISTS trash (
     id INTEGER PRIMARY KEY,
-    content TEXT NOT NULL
-);
\ No newline at end of file
+    pathkey TEXT NOT NULL,
+    paths TEXT NOT NULL,
+    indexs TEXT NOT NULL,
+    ctime INTEGER NOT NULL,
+    mtime INTEGER NOT NULL,
+    -- device id of the client that deleted it
+    deleted_by TEXT NOT NULL,
+    -- in milliseconds
+    deleted_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS trash_pathkey ON trash (pathkey);
+-- open chunked sync session of each device
+CREATE TABLE IF NOT EXISTS chunk_sessions (
+    device TEXT PRIMARY KEY,
+    skey TEXT NOT NULL,
+    -- in milliseconds,idle sessions are dropped
+    last_used INTEGER NOT NULL
+);
+-- files sent by applyChunk,written to meta when the session finishes
+CREATE TABLE IF NOT EXISTS staged (
+    id INTEGER PRIMARY KEY,
+    device TEXT NOT NULL,
+    -- FileInfo as json
+    fileinfo TEXT NOT NULL,
+    content BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS staged_device ON staged (device);
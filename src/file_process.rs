@@ -1,12 +1,20 @@
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 use crate::{
     db::{DbManager, Meta},
     error::ApplicationError,
-    protocol::{DownloadRequest, FileAction, MetaInner, MetaRequest, MetaResponse, UploadRequest},
+    protocol::{
+        ApplyChunkRequest, ChunkRequest, ChunkResponse, DownloadRequest, DownloadResponse,
+        FileAction, FileInfo, HistoryRequest, HistoryResponse, MetaInner, MetaRequest,
+        MetaResponse, Pfile, RestoreRequest, TrashResponse, UndeleteRequest, UploadRequest,
+        UploadResponse,
+    },
+    request::SyncRequest,
 };
-use actix_web::HttpResponse;
 use rusqlite::Connection;
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
 use serde::{Deserialize, Serialize};
 
 /// retrieve file meta data about files from server.
@@ -15,22 +23,30 @@ use serde::{Deserialize, Serialize};
 
 /// client tends to send duplicated request,that means file info and file action are all the same
 /// in two MetaInner.Use set before send back to client 
+///
+/// With a cursor the client only sends its own changes,and gets back what changed on the
+/// server since,instead of both sides comparing the whole file list.
 pub(crate) fn server_meta(
     meta_request: MetaRequest,
+    device: &str,
     db: &DbManager,
-) -> Result<HttpResponse, ApplicationError> {
+) -> Result<MetaResponse, ApplicationError> {
+    // before anything of the request is applied,the client sends it again with a full meta
+    if let Some(cursor) = meta_request.cursor {
+        if db.cursor_expired(cursor)? {
+            return Err(ApplicationError::CursorExpired(format!(
+                "changes since {cursor} are no longer known,send meta without cursor"
+            )));
+        }
+    }
     // remove duplicated items
     let mut s=HashSet::new();
     s.extend(meta_request.states);
-    let meta_request=MetaRequest{states:s.into_iter().collect()};
+    let (last_sync, cursor) = (meta_request.last_sync, meta_request.cursor);
+    let meta_request=MetaRequest{states:s.into_iter().collect(),last_sync,cursor};
     // First process metaInner whose action is not Absent.
     // no needing to compare with server
 
-    let server_meta = db.get_meta()?.unwrap_or_default();
-    let mut server_set = HashSet::new();
-    server_meta.iter().for_each(|e| {
-        server_set.insert(e.fname());
-    });
     let non_absent = meta_request
         .states
         .iter()
@@ -44,30 +60,76 @@ pub(crate) fn server_meta(
         .filter(|e| e.action == FileAction::Delete)
         .collect::<Vec<_>>();
     // filter out those only exist in server db
-    let valid_delete = delete
+    let mut valid_delete = vec![];
+    for e in delete {
+        if db.has_file(&e.fileinfo.key())? {
+            valid_delete.push(e);
+        }
+    }
+    db.update_stetes(&valid_delete, device)?;
+    // renamed or moved files,repoint the server record instead of asking for an upload,
+    // unless the content changed too
+    let mut renamed = HashSet::new();
+    for e in non_absent
         .iter()
-        .map(|e| e.to_owned())
-        .filter(|e| server_set.contains(&e.fileinfo.name))
-        .collect::<Vec<_>>();
-    db.update_stetes(&valid_delete)?;
+        .filter(|e| e.action != FileAction::Delete && !e.fileinfo.oldpath.is_empty())
+    {
+        if db.rename(&e.fileinfo)? {
+            renamed.insert(e.fileinfo.key());
+        }
+    }
     // process upload,modify
 
     let upload0 = non_absent
         .iter()
         .map(|e| e.to_owned())
-        .filter(|e| e.action == FileAction::Upload)
+        .filter(|e| e.action == FileAction::Upload && !renamed.contains(&e.fileinfo.key()))
         .collect::<Vec<_>>();
     let modify0 = non_absent
         .iter()
         .map(|e| e.to_owned())
-        .filter(|e| e.action == FileAction::Modify)
+        .filter(|e| e.action == FileAction::Modify && !renamed.contains(&e.fileinfo.key()))
         .collect::<Vec<_>>();
+    // files the client reported itself get one verdict,they are left out of the comparison below
+    let reported = non_absent
+        .iter()
+        .map(|e| e.fileinfo.key())
+        .collect::<HashSet<_>>();
+
+    if let Some(cursor) = cursor {
+        let (both, changes): (Vec<_>, Vec<_>) = db
+            .changes_since(cursor, device)?
+            .into_iter()
+            .partition(|e| reported.contains(&e.fileinfo.key()));
+        // files changed on both sides get a verdict,the others are settled by the upload
+        // that follows
+        let both = both
+            .iter()
+            .map(|e| e.fileinfo.key())
+            .collect::<HashSet<_>>();
+        let mut changed = vec![];
+        for e in upload0.iter().chain(&modify0) {
+            if !both.contains(&e.fileinfo.key()) {
+                changed.push(e.clone());
+            } else if let Some(e) = verdict(e, last_sync, db)? {
+                changed.push(e);
+            }
+        }
+        let mut all = vec![];
+        all.extend_from_slice(&valid_delete);
+        all.extend_from_slice(&changed);
+        all.extend_from_slice(&changes);
+        return Ok(MetaResponse {
+            metainner: all,
+            cursor: Some(db.cursor()?),
+        });
+    }
 
     //  db records should be retrieved again after server update its meta.
     let meta_request = meta_request
         .states
         .iter()
-        .filter(|e| e.action == FileAction::Absent)
+        .filter(|e| e.action == FileAction::Absent && !reported.contains(&e.fileinfo.key()))
         .collect::<Vec<_>>();
     // just use empty vec if no data
     let server_meta = db.get_meta()?.unwrap_or_default();
@@ -75,10 +137,10 @@ pub(crate) fn server_meta(
     let mut server_set = HashSet::new();
     let mut client_set = HashSet::new();
     server_meta.iter().for_each(|e| {
-        server_set.insert(e.fname());
+        server_set.insert(e.key());
     });
     meta_request.iter().for_each(|e| {
-        client_set.insert(e.fileinfo.name());
+        client_set.insert(e.fileinfo.key());
     });
 
     // 1. files exist in both sides.交集,modify
@@ -86,7 +148,10 @@ pub(crate) fn server_meta(
     // 2. files exist in client sides.upload
     let client = client_set.difference(&server_set).collect::<Vec<_>>();
     // 3. files exist in  server sides.skip if marked,rename delete,download if not marked the above
-    let server = server_set.difference(&client_set).collect::<Vec<_>>();
+    let server = server_set
+        .difference(&client_set)
+        .filter(|e| !reported.contains(*e))
+        .collect::<Vec<_>>();
 
     // filter out elements from structs
 
@@ -94,15 +159,15 @@ pub(crate) fn server_meta(
     // old delete and upload new one),modify in client,send two requests delete and modify for a file.
     let both_files = server_meta
         .iter()
-        .filter(|e| both.contains(&&e.fname()))
+        .filter(|e| both.contains(&&e.key()))
         .collect::<Vec<_>>();
     let client_files = meta_request
         .iter()
-        .filter(|e| client.contains(&&e.fileinfo.name))
+        .filter(|e| client.contains(&&e.fileinfo.key()))
         .collect::<Vec<_>>();
     let server_files = server_meta
         .iter()
-        .filter(|e| server.contains(&&e.fname()))
+        .filter(|e| server.contains(&&e.key()))
         .collect::<Vec<_>>();
 
     //    mark them
@@ -112,11 +177,23 @@ pub(crate) fn server_meta(
         .map(|e| MetaInner::new(FileAction::Delete, e))
         .collect::<Vec<_>>();
 
-    let modify = both_files
+    // compare what is on both sides,identical files are left out
+    let client_states = meta_request
         .iter()
-        .filter(|e| e.states != FileAction::Delete)
-        .map(|e| MetaInner::new(crate::protocol::FileAction::Modify, e))
-        .collect::<Vec<_>>();
+        .map(|e| (e.fileinfo.key(), &e.fileinfo))
+        .collect::<HashMap<_, _>>();
+    let mut modify = vec![];
+    for e in both_files.iter().filter(|e| e.states != FileAction::Delete) {
+        let client = client_states[&e.key()];
+        let server = FileInfo::from(*e);
+        match compare(client, &server, last_sync) {
+            Some(FileAction::Upload) => {
+                modify.push(MetaInner::from_fileinfo(FileAction::Upload, client))
+            }
+            Some(action) => modify.push(MetaInner::from_fileinfo(action, &server)),
+            None => {}
+        }
+    }
     let upload = client_files
         .iter()
         .map(|e| MetaInner::from_fileinfo(crate::protocol::FileAction::Upload, &e.fileinfo))
@@ -127,30 +204,270 @@ pub(crate) fn server_meta(
         .map(|e| MetaInner::new(FileAction::Download, e))
         .collect::<Vec<_>>();
 println!("download {:?}",download);
+    // the client's own changes are checked against the server record as well
+    let mut changed = vec![];
+    for e in upload0.iter().chain(&modify0) {
+        if let Some(e) = verdict(e, last_sync, db)? {
+            changed.push(e);
+        }
+    }
 
     let mut all = vec![];
     all.extend_from_slice(&valid_delete);
     all.extend_from_slice(&upload);
-    all.extend_from_slice(&upload0);
-    all.extend_from_slice(&modify0);
+    all.extend_from_slice(&changed);
     all.extend_from_slice(&delete);
     all.extend_from_slice(&modify);
     all.extend_from_slice(&download);
 
-    let resp = MetaResponse { metainner: all };
-    Ok(HttpResponse::Ok().json(resp))
+    Ok(MetaResponse {
+        metainner: all,
+        cursor: Some(db.cursor()?),
+    })
+}
+/// Verdict for a file present on both sides,None if both have the same content.
+///
+/// Newer mtime wins,unless both sides changed the file since the client's last sync
+/// or the mtimes are equal while the hashes differ.
+fn compare(client: &FileInfo, server: &FileInfo, last_sync: i64) -> Option<FileAction> {
+    let hash_known = !client.hash.is_empty() && !server.hash.is_empty();
+    if hash_known && client.hash == server.hash {
+        return None;
+    }
+    if last_sync > 0 && client.mtime > last_sync && server.mtime > last_sync {
+        return Some(FileAction::Conflict);
+    }
+    match client.mtime.cmp(&server.mtime) {
+        Ordering::Greater => Some(FileAction::Upload),
+        Ordering::Less => Some(FileAction::Download),
+        Ordering::Equal if hash_known => Some(FileAction::Conflict),
+        Ordering::Equal => None,
+    }
 }
-/// Todo: just create a copy of meta record if old path is present in req .   
-pub(crate) fn upload(req: UploadRequest, db: &DbManager) -> Result<HttpResponse, ApplicationError> {
-    let _ = db.upload(req)?;
-    Ok(HttpResponse::Ok().finish())
+/// Verdict for a file the client reported as upload or modify.
+///
+/// It is sent back as it is if the server has no live record of it or the client is newer,
+/// otherwise the server record comes back marked download or conflict.
+fn verdict(
+    e: &MetaInner,
+    last_sync: i64,
+    db: &DbManager,
+) -> Result<Option<MetaInner>, ApplicationError> {
+    let server = match db.current(&e.fileinfo.key())? {
+        Some(meta) => FileInfo::from(&meta),
+        None => return Ok(Some(e.clone())),
+    };
+    Ok(match compare(&e.fileinfo, &server, last_sync) {
+        Some(FileAction::Upload) => Some(e.clone()),
+        Some(action) => Some(MetaInner::from_fileinfo(action, &server)),
+        None => None,
+    })
+}
+/// If old path is present in req,the record at old path is kept as a delete mark
+/// and content is shared with the new path.
+///
+/// Notes edited on another device in the meantime are merged,see `UploadResponse`.
+pub(crate) fn upload(
+    req: &SyncRequest<UploadRequest>,
+    db: &DbManager,
+) -> Result<UploadResponse, ApplicationError> {
+    let files = each_file(req, db, |file| db.upload(file, &req.device))?;
+    Ok(UploadResponse { files })
+}
+/// Parse `files` of an upload or applyChunk body one at a time and hand each to `each`
+/// as soon as it is read,so that a big request holds one file in memory,not all of them.
+/// All of it happens in one transaction,a malformed body leaves nothing behind.
+fn each_file<T, F>(
+    req: &SyncRequest<T>,
+    db: &DbManager,
+    each: F,
+) -> Result<Vec<MetaInner>, ApplicationError>
+where
+    T: serde::de::DeserializeOwned,
+    F: FnMut(Pfile) -> rusqlite::Result<Vec<MetaInner>>,
+{
+    db.in_transaction(|| {
+        let mut files = EachFile {
+            each,
+            merged: vec![],
+            error: None,
+        };
+        let parsed = req.json_seed(&mut files);
+        // a db error is what stopped parsing,report it rather than a json error
+        if let Some(e) = files.error {
+            return Err(e.into());
+        }
+        parsed?;
+        Ok(files.merged)
+    })
+}
+/// `{"files": [...]}` visited file by file
+struct EachFile<F> {
+    each: F,
+    merged: Vec<MetaInner>,
+    error: Option<rusqlite::Error>,
+}
+impl<'de, F> DeserializeSeed<'de> for &mut EachFile<F>
+where
+    F: FnMut(Pfile) -> rusqlite::Result<Vec<MetaInner>>,
+{
+    type Value = ();
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+impl<'de, F> Visitor<'de> for &mut EachFile<F>
+where
+    F: FnMut(Pfile) -> rusqlite::Result<Vec<MetaInner>>,
+{
+    type Value = ();
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an object with files")
+    }
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut found = false;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "files" {
+                map.next_value_seed(Files(&mut *self))?;
+                found = true;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        if !found {
+            return Err(de::Error::missing_field("files"));
+        }
+        Ok(())
+    }
+}
+struct Files<'a, F>(&'a mut EachFile<F>);
+impl<'de, F> DeserializeSeed<'de> for Files<'_, F>
+where
+    F: FnMut(Pfile) -> rusqlite::Result<Vec<MetaInner>>,
+{
+    type Value = ();
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+impl<'de, F> Visitor<'de> for Files<'_, F>
+where
+    F: FnMut(Pfile) -> rusqlite::Result<Vec<MetaInner>>,
+{
+    type Value = ();
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of files")
+    }
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(file) = seq.next_element::<Pfile>()? {
+            match (self.0.each)(file) {
+                Ok(merged) => self.0.merged.extend(merged),
+                Err(e) => {
+                    let message = e.to_string();
+                    self.0.error = Some(e);
+                    return Err(de::Error::custom(message));
+                }
+            }
+        }
+        Ok(())
+    }
 }
 pub(crate) fn download(
     req: DownloadRequest,
+    device: &str,
+    db: &DbManager,
+) -> Result<DownloadResponse, ApplicationError> {
+    Ok(db.download(req, device)?)
+}
+pub(crate) fn history(
+    req: HistoryRequest,
+    db: &DbManager,
+) -> Result<HistoryResponse, ApplicationError> {
+    Ok(db.history(req)?)
+}
+pub(crate) fn restore(
+    req: RestoreRequest,
+    db: &DbManager,
+) -> Result<MetaInner, ApplicationError> {
+    let (path, revision) = (req.path.to_string(), req.revision);
+    match db.restore(req)? {
+        Some(fileinfo) => Ok(MetaInner::from_fileinfo(FileAction::Download, &fileinfo)),
+        None => Err(ApplicationError::NotFound(format!(
+            "no revision {revision} of {path}"
+        ))),
+    }
+}
+pub(crate) fn trash(db: &DbManager) -> Result<TrashResponse, ApplicationError> {
+    Ok(db.trash()?)
+}
+pub(crate) fn undelete(
+    req: UndeleteRequest,
+    db: &DbManager,
+) -> Result<MetaResponse, ApplicationError> {
+    let metainner = db
+        .undelete(req)?
+        .iter()
+        .map(|e| MetaInner::from_fileinfo(FileAction::Download, e))
+        .collect();
+    Ok(MetaResponse {
+        metainner,
+        cursor: None,
+    })
+}
+/// max number of files sent back in one chunk
+const CHUNK_SIZE: usize = 50;
+pub(crate) fn chunk(
+    req: ChunkRequest,
+    skey: &str,
+    device: &str,
+    db: &DbManager,
+) -> Result<ChunkResponse, ApplicationError> {
+    check_session_key(skey)?;
+    db.open_session(device, skey)?;
+    let mut paths = req.paths;
+    let remaining = paths.split_off(paths.len().min(CHUNK_SIZE));
+    let files = db.download(DownloadRequest { paths }, device)?.files;
+    Ok(ChunkResponse { files, remaining })
+}
+/// Files are staged,not written to meta before finish.
+/// Merges are only known then,so `files` of the response is always empty.
+pub(crate) fn apply_chunk(
+    req: &SyncRequest<ApplyChunkRequest>,
+    db: &DbManager,
+) -> Result<UploadResponse, ApplicationError> {
+    let (skey, device) = (&req.session_key, &req.device);
+    check_session_key(skey)?;
+    db.open_session(device, skey)?;
+    each_file(req, db, |file| {
+        db.stage(device, &file)?;
+        Ok(vec![])
+    })?;
+    Ok(UploadResponse { files: vec![] })
+}
+/// write the staged files of the session,response is the same as that of upload
+pub(crate) fn finish(
+    skey: &str,
+    device: &str,
     db: &DbManager,
-) -> Result<HttpResponse, ApplicationError> {
-    let res = db.download(req)?;
-    Ok(HttpResponse::Ok().json(res))
+) -> Result<UploadResponse, ApplicationError> {
+    check_session_key(skey)?;
+    match db.finish_session(device, skey)? {
+        Some(files) => Ok(UploadResponse { files }),
+        None => Err(ApplicationError::InvalidSession(format!(
+            "no open session for key {skey}"
+        ))),
+    }
+}
+pub(crate) fn abort(skey: &str, device: &str, db: &DbManager) -> Result<(), ApplicationError> {
+    Ok(db.abort_session(device, skey)?)
+}
+fn check_session_key(skey: &str) -> Result<(), ApplicationError> {
+    if skey.is_empty() {
+        return Err(ApplicationError::InvalidSession(
+            "missing session key".to_string(),
+        ));
+    }
+    Ok(())
 }
 
 #[test]
@@ -164,4 +481,299 @@ fn defy_deplicated_metainner() {
     s1.insert(MetaInner::default());
     s.extend(v);
     assert_eq!(s,s1);
-}
\ No newline at end of file
+}
+#[test]
+fn compare_client_and_server_file() {
+    let server = FileInfo {
+        mtime: 100,
+        hash: "a".to_string(),
+        ..Default::default()
+    };
+    let mut client = server.clone();
+    assert_eq!(compare(&client, &server, 0), None);
+    client.hash = "b".to_string();
+    client.mtime = 200;
+    assert_eq!(compare(&client, &server, 0), Some(FileAction::Upload));
+    client.mtime = 50;
+    assert_eq!(compare(&client, &server, 0), Some(FileAction::Download));
+    client.mtime = 100;
+    assert_eq!(compare(&client, &server, 0), Some(FileAction::Conflict));
+    // both changed since last sync
+    client.mtime = 200;
+    assert_eq!(compare(&client, &server, 90), Some(FileAction::Conflict));
+    assert_eq!(compare(&client, &server, 150), Some(FileAction::Upload));
+}
+#[test]
+fn rename_with_edit_asks_for_upload() {
+    use crate::db::content_hash;
+    use crate::protocol::FileContent;
+
+    let dir = tempfile::tempdir().unwrap();
+    let db = DbManager::new(dir.path()).unwrap();
+    let file = |path: &str, oldpath: &str, mtime: i64, content: &[u8]| FileInfo {
+        name: path.to_string(),
+        path: path.to_string(),
+        oldpath: oldpath.to_string(),
+        mtime,
+        hash: content_hash(content),
+        ..Default::default()
+    };
+    let files = ["a.md", "c.md"].map(|path| Pfile {
+        states: file(path, "", 10, b"x"),
+        content: FileContent(b"x".to_vec()),
+    });
+    for file in files {
+        db.upload(file, "pc").unwrap();
+    }
+    let renamed = |fileinfo: FileInfo| MetaRequest {
+        states: vec![MetaInner::from_fileinfo(FileAction::Modify, &fileinfo)],
+        last_sync: 0,
+        cursor: Some(0),
+    };
+    // what the client is told to do with the new path
+    let actions = |fileinfo: FileInfo| {
+        let key = fileinfo.key();
+        server_meta(renamed(fileinfo), "pc", &db)
+            .unwrap()
+            .metainner
+            .into_iter()
+            .filter(|e| e.fileinfo.key() == key)
+            .map(|e| e.action)
+            .collect::<Vec<_>>()
+    };
+    // only renamed
+    assert_eq!(actions(file("b.md", "a.md", 20, b"x")), []);
+    // renamed and edited
+    assert_eq!(actions(file("d.md", "c.md", 20, b"y")), [FileAction::Modify]);
+    let meta = db.get_meta().unwrap().unwrap();
+    let d = meta.iter().find(|m| m.key() == "d.md").unwrap();
+    assert_eq!((d.mtime(), d.hash()), (10, content_hash(b"x")));
+}
+#[test]
+fn upload_files_one_at_a_time() {
+    use crate::request::Body;
+    use std::io::Write;
+    use std::marker::PhantomData;
+    use std::sync::Arc;
+
+    let dir = tempfile::tempdir().unwrap();
+    let db = DbManager::new(dir.path()).unwrap();
+    let request = |data: Body| SyncRequest::<UploadRequest> {
+        data,
+        json_output_type: PhantomData,
+        sync_key: String::new(),
+        session_key: String::new(),
+        device: "pc".to_string(),
+        ip: String::new(),
+    };
+    let body = |paths: &[&str]| {
+        let files = paths
+            .iter()
+            .map(|p| {
+                format!(
+                    r#"{{"states":{{"name":"{p}","path":"{p}","mtime":1,"ctime":1,"oldpath":""}},"content":"aGk=","encoding":"base64"}}"#
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(r#"{{"other":[1,{{}}],"files":[{}]}}"#, files.join(","))
+    };
+    let mut spooled = tempfile::tempfile().unwrap();
+    spooled.write_all(body(&["a.md", "b.png"]).as_bytes()).unwrap();
+    upload(&request(Body::Spooled(Arc::new(spooled))), &db).unwrap();
+    assert!(db.has_file("a.md").unwrap());
+    assert!(db.has_file("b.png").unwrap());
+    // cut off in the middle of the second file,the first one is not kept either
+    let mut cut = body(&["c.md", "d.md"]).into_bytes();
+    cut.truncate(cut.len() - 20);
+    assert!(upload(&request(Body::Memory(cut)), &db).is_err());
+    assert!(!db.has_file("c.md").unwrap());
+    assert!(upload(&request(Body::Memory(b"{}".to_vec())), &db).is_err());
+}
+#[test]
+fn meta_with_cursor() {
+    use crate::protocol::{FileContent, Pfile};
+
+    let dir = tempfile::tempdir().unwrap();
+    let db = DbManager::new(dir.path()).unwrap();
+    let fileinfo = |path: &str| FileInfo {
+        name: path.to_string(),
+        path: path.to_string(),
+        mtime: 1,
+        ..Default::default()
+    };
+    let meta = |states: Vec<MetaInner>, cursor: i64, device: &str| {
+        let req = MetaRequest {
+            states,
+            last_sync: 0,
+            cursor: Some(cursor),
+        };
+        server_meta(req, device, &db)
+    };
+    for path in ["a.md", "b.md"] {
+        let file = Pfile {
+            states: fileinfo(path),
+            content: FileContent(b"x".to_vec()),
+        };
+        db.upload(file, "pc").unwrap();
+    }
+    let resp = meta(vec![], 0, "phone").unwrap();
+    assert_eq!(resp.metainner.len(), 2);
+    assert!(resp.metainner.iter().all(|e| e.action == FileAction::Download));
+    let cursor = resp.cursor.unwrap();
+    // its own uploads are left out
+    assert!(meta(vec![], 0, "pc").unwrap().metainner.is_empty());
+    // a delete of the phone reaches the pc
+    let delete = MetaInner::from_fileinfo(FileAction::Delete, &fileinfo("a.md"));
+    let resp = meta(vec![delete], cursor, "phone").unwrap();
+    assert_eq!(resp.metainner.len(), 1);
+    let resp = meta(vec![], cursor, "pc").unwrap();
+    assert_eq!(resp.metainner.len(), 1);
+    assert_eq!(resp.metainner[0].action, FileAction::Delete);
+    assert_eq!(resp.metainner[0].fileinfo.key(), "a.md");
+    // a cursor the server never handed out
+    let e = meta(vec![], resp.cursor.unwrap() + 1, "pc").unwrap_err();
+    assert!(matches!(e, ApplicationError::CursorExpired(_)));
+}
+#[test]
+fn client_changes_checked_against_server() {
+    use crate::db::content_hash;
+    use crate::protocol::FileContent;
+
+    let dir = tempfile::tempdir().unwrap();
+    let db = DbManager::new(dir.path()).unwrap();
+    let file = |mtime: i64, content: &[u8]| FileInfo {
+        name: "x.png".to_string(),
+        path: "x.png".to_string(),
+        mtime,
+        hash: content_hash(content),
+        ..Default::default()
+    };
+    for (device, mtime, content) in [("pc", 10, b"a"), ("phone", 100, b"b")] {
+        let file = Pfile {
+            states: file(mtime, content),
+            content: FileContent(content.to_vec()),
+        };
+        db.upload(file, device).unwrap();
+    }
+    // what pc is told to do with its edit of x.png
+    let actions = |fileinfo: FileInfo, last_sync: i64| {
+        let req = MetaRequest {
+            states: vec![MetaInner::from_fileinfo(FileAction::Modify, &fileinfo)],
+            last_sync,
+            cursor: None,
+        };
+        server_meta(req, "pc", &db)
+            .unwrap()
+            .metainner
+            .into_iter()
+            .map(|e| (e.action, e.fileinfo.mtime))
+            .collect::<Vec<_>>()
+    };
+    // both edited since the last sync
+    assert_eq!(actions(file(90, b"c"), 50), [(FileAction::Conflict, 100)]);
+    assert_eq!(actions(file(90, b"c"), 0), [(FileAction::Download, 100)]);
+    assert_eq!(actions(file(200, b"c"), 150), [(FileAction::Modify, 200)]);
+    assert_eq!(actions(file(90, b"b"), 50), []);
+}
+#[test]
+fn cursor_meta_checks_files_changed_on_both_sides() {
+    use crate::db::content_hash;
+    use crate::protocol::FileContent;
+
+    let dir = tempfile::tempdir().unwrap();
+    let db = DbManager::new(dir.path()).unwrap();
+    let file = |path: &str, mtime: i64, content: &[u8]| FileInfo {
+        name: path.to_string(),
+        path: path.to_string(),
+        mtime,
+        hash: content_hash(content),
+        ..Default::default()
+    };
+    let upload = |fileinfo: FileInfo, content: &[u8], device: &str| {
+        let file = Pfile {
+            states: fileinfo,
+            content: FileContent(content.to_vec()),
+        };
+        db.upload(file, device).unwrap();
+    };
+    upload(file("x.png", 10, b"a"), b"a", "pc");
+    upload(file("y.png", 10, b"a"), b"a", "pc");
+    let cursor = db.cursor().unwrap();
+    upload(file("x.png", 100, b"b"), b"b", "phone");
+    let req = MetaRequest {
+        states: vec![
+            MetaInner::from_fileinfo(FileAction::Modify, &file("x.png", 90, b"c")),
+            MetaInner::from_fileinfo(FileAction::Modify, &file("y.png", 90, b"c")),
+        ],
+        last_sync: 50,
+        cursor: Some(cursor),
+    };
+    let mut actions = server_meta(req, "pc", &db)
+        .unwrap()
+        .metainner
+        .into_iter()
+        .map(|e| (e.fileinfo.key(), e.action, e.fileinfo.mtime))
+        .collect::<Vec<_>>();
+    actions.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        actions,
+        [
+            ("x.png".to_string(), FileAction::Conflict, 100),
+            ("y.png".to_string(), FileAction::Modify, 90)
+        ]
+    );
+}
+#[test]
+fn history_and_restore() {
+    use crate::db::content_hash;
+    use crate::protocol::FileContent;
+
+    let dir = tempfile::tempdir().unwrap();
+    let db = DbManager::new(dir.path()).unwrap();
+    for (mtime, content) in [(1, b"v1"), (2, b"v2"), (3, b"v3")] {
+        let file = Pfile {
+            states: FileInfo {
+                name: "a.md".to_string(),
+                path: "Notes/a.md".to_string(),
+                mtime,
+                ..Default::default()
+            },
+            content: FileContent(content.to_vec()),
+        };
+        db.upload(file, "pc").unwrap();
+    }
+    let hashes = |db: &DbManager| {
+        let req = HistoryRequest {
+            path: "Notes\\a.md".to_string(),
+        };
+        history(req, db)
+            .unwrap()
+            .revisions
+            .into_iter()
+            .map(|r| (r.id, r.hash))
+            .collect::<Vec<_>>()
+    };
+    // newest first
+    let (ids, before): (Vec<_>, Vec<_>) = hashes(&db).into_iter().unzip();
+    assert_eq!(before, [content_hash(b"v2"), content_hash(b"v1")]);
+
+    let req = RestoreRequest {
+        path: "Notes/a.md".to_string(),
+        revision: ids[1],
+    };
+    let restored = restore(req, &db).unwrap();
+    assert_eq!(restored.action, FileAction::Download);
+    assert_eq!(restored.fileinfo.hash, content_hash(b"v1"));
+    assert_eq!(db.current("Notes/a.md").unwrap().unwrap().hash(), content_hash(b"v1"));
+    // the replaced content is a revision now
+    let after = hashes(&db).into_iter().map(|(_, h)| h).collect::<Vec<_>>();
+    assert_eq!(
+        after,
+        [content_hash(b"v3"), content_hash(b"v2"), content_hash(b"v1")]
+    );
+    let req = RestoreRequest {
+        path: "Notes/a.md".to_string(),
+        revision: ids[0] + 100,
+    };
+    assert!(matches!(restore(req, &db), Err(ApplicationError::NotFound(_))));
+}
@@ -0,0 +1,245 @@
+// Throw garbage headers,bodies and db rows at the server,none of it may panic
+// or be answered with a server error unless the db itself is broken.
+
+use actix_web::{dev::Service, http::header::HeaderValue, test, web, App};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rusqlite::Connection;
+use std::path::Path;
+
+use crate::{
+    config::{ConfigLimits, ConfigLogin},
+    handler::sync_handler,
+    protocol::Server,
+    ratelimit::LoginLimiter,
+    request::{SyncRequestWrapper, SYNC_HEADER_NAME},
+    user::{add_user, create_auth_db},
+};
+
+const ROUNDS: usize = 300;
+
+const FILEINFO: &str = r#"{"name":"a.md","path":"Daily/a.md","mtime":10,"ctime":1,"oldpath":""}"#;
+
+/// a valid body of each method,to be mangled
+fn templates() -> Vec<(&'static str, String)> {
+    vec![
+        ("hostKey", r#"{"username":"bob","password":"pw"}"#.to_string()),
+        (
+            "meta",
+            format!(r#"{{"states":[{{"action":"upload","fileinfo":{FILEINFO}}}],"last_sync":5}}"#),
+        ),
+        (
+            "upload",
+            format!(r#"{{"files":[{{"states":{FILEINFO},"content":"aGk=","encoding":"base64"}}]}}"#),
+        ),
+        ("download", r#"{"paths":["Daily/a.md"]}"#.to_string()),
+        ("chunk", r#"{"paths":["Daily/a.md","b.md"]}"#.to_string()),
+        (
+            "applyChunk",
+            format!(r#"{{"files":[{{"states":{FILEINFO},"content":"hi"}}]}}"#),
+        ),
+        ("finish", String::new()),
+        ("abort", String::new()),
+        ("history", r#"{"path":"Daily/a.md"}"#.to_string()),
+        ("restore", r#"{"path":"Daily/a.md","revision":1}"#.to_string()),
+        ("trash", String::new()),
+        ("undelete", r#"{"paths":["Daily/a.md"]}"#.to_string()),
+    ]
+}
+
+const FRAGMENTS: &[&str] = &[
+    "null",
+    "-1",
+    "1e999",
+    "9223372036854775808",
+    "\"\\u0000\"",
+    "\"../../etc/passwd\"",
+    "[]",
+    "{}",
+    "\"\\ud800\"",
+    "\"",
+    ",",
+];
+
+fn mangle(rng: &mut StdRng, body: &str) -> Vec<u8> {
+    let mut bytes = body.as_bytes().to_vec();
+    match rng.gen_range(0..4) {
+        // cut off
+        0 => bytes.truncate(rng.gen_range(0..=bytes.len())),
+        // flip some bytes
+        1 => {
+            for _ in 0..rng.gen_range(1..4) {
+                if !bytes.is_empty() {
+                    let i = rng.gen_range(0..bytes.len());
+                    bytes[i] = rng.gen();
+                }
+            }
+        }
+        // put a value of the wrong kind somewhere
+        2 => {
+            let i = rng.gen_range(0..=bytes.len());
+            let fragment = FRAGMENTS[rng.gen_range(0..FRAGMENTS.len())];
+            bytes.splice(i..i, fragment.bytes());
+        }
+        _ => bytes = (0..rng.gen_range(0..64)).map(|_| rng.gen()).collect(),
+    }
+    bytes
+}
+
+fn setup(dir: &Path) -> Server {
+    let auth_db = dir.join("auth.db");
+    create_auth_db(&auth_db).unwrap();
+    add_user(&["bob".to_string(), "pw".to_string()], &auth_db).unwrap();
+    // no lockout,the fuzzer fails a lot of logins
+    let limiter = LoginLimiter::new(&ConfigLogin {
+        max_failures_per_ip: 0,
+        max_failures_per_user: 0,
+        window_secs: 0,
+        lockout_secs: 0,
+    });
+    Server::new_from_db(&dir.join("collections"), auth_db.to_str().unwrap(), 0, limiter).unwrap()
+}
+
+macro_rules! app {
+    ($server:expr) => {
+        test::init_service(
+            App::new()
+                .app_data(web::Data::new($server))
+                .service(web::resource("/{mehod}").to(sync_handler))
+                .wrap(SyncRequestWrapper::new(ConfigLimits::default())),
+        )
+        .await
+    };
+}
+
+/// status of the response,errors of the middleware included
+macro_rules! status {
+    ($app:expr, $req:expr) => {
+        match $app.call($req).await {
+            Ok(resp) => resp.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    };
+}
+
+macro_rules! login {
+    ($app:expr) => {{
+        let req = test::TestRequest::post()
+            .uri("/hostKey")
+            .set_payload(r#"{"username":"bob","password":"pw"}"#)
+            .to_request();
+        let resp: serde_json::Value = test::call_and_read_body_json(&$app, req).await;
+        resp["key"].as_str().unwrap().to_string()
+    }};
+}
+
+#[actix_web::test]
+async fn garbage_headers() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = app!(setup(dir.path()));
+    let token = login!(app);
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut headers = vec![
+        "".to_string(),
+        "null".to_string(),
+        "{}".to_string(),
+        r#"{"k":1}"#.to_string(),
+        r#"{"k":null,"s":[],"d":{}}"#.to_string(),
+        format!(r#"{{"k":"{token}","s":1}}"#),
+        format!(r#"{{"k":"{token}""#),
+        "\"".repeat(5000),
+    ];
+    for _ in 0..ROUNDS {
+        let base = format!(r#"{{"k":"{token}","s":"s1","d":"pc"}}"#);
+        headers.push(String::from_utf8_lossy(&mangle(&mut rng, &base)).to_string());
+    }
+    let mut values = headers
+        .iter()
+        .filter_map(|h| HeaderValue::from_str(h).ok())
+        .collect::<Vec<_>>();
+    // bytes that are allowed in a header but are no str
+    for _ in 0..ROUNDS {
+        let bytes = (0..rng.gen_range(1..32))
+            .map(|_| rng.gen_range(0x80..=0xff))
+            .collect::<Vec<u8>>();
+        values.push(HeaderValue::from_bytes(&bytes).unwrap());
+    }
+    let methods = templates();
+    for value in values {
+        let (method, body) = &methods[rng.gen_range(0..methods.len())];
+        let req = test::TestRequest::post()
+            .uri(&format!("/{method}"))
+            .insert_header((SYNC_HEADER_NAME.clone(), value.clone()))
+            .set_payload(body.to_string())
+            .to_request();
+        let status = status!(app, req);
+        assert!(
+            !status.is_server_error(),
+            "{method} with header {value:?}: {status}"
+        );
+    }
+}
+
+#[actix_web::test]
+async fn garbage_bodies() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = app!(setup(dir.path()));
+    let token = login!(app);
+    let header = format!(r#"{{"k":"{token}","s":"s1","d":"pc"}}"#);
+    let mut rng = StdRng::seed_from_u64(2);
+    let methods = templates();
+    for _ in 0..ROUNDS {
+        for (method, body) in &methods {
+            let body = mangle(&mut rng, body);
+            let req = test::TestRequest::post()
+                .uri(&format!("/{method}"))
+                .insert_header((SYNC_HEADER_NAME.clone(), header.as_str()))
+                .set_payload(body.clone())
+                .to_request();
+            let status = status!(app, req);
+            assert!(
+                !status.is_server_error(),
+                "{method} with body {}: {status}",
+                String::from_utf8_lossy(&body)
+            );
+        }
+    }
+}
+
+#[actix_web::test]
+async fn corrupt_db_rows() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = app!(setup(dir.path()));
+    let token = login!(app);
+    let conn = Connection::open(dir.path().join("collections/bob/obsidian.db")).unwrap();
+    let rows = [
+        "(100,'s.md','deadbeef','s.md','garbage',1,1,'s.md')",
+        "(101,'c.md','deadbeef','c.md','\"upload\"','abc',1,'c.md')",
+        // content is gone
+        "(102,'m.md','deadbeef','m.md','\"upload\"',1,1,'m.md')",
+    ];
+    let header = format!(r#"{{"k":"{token}","d":"pc"}}"#);
+    for row in rows {
+        conn.execute_batch("DELETE FROM meta").unwrap();
+        conn.execute_batch(&format!(
+            "INSERT INTO meta (id, fname, indexs, paths, states, ctime, mtime, pathkey) VALUES {row}"
+        ))
+        .unwrap();
+        for (method, body) in [
+            ("meta", r#"{"states":[]}"#),
+            ("download", r#"{"paths":["s.md","c.md","m.md"]}"#),
+            ("history", r#"{"path":"m.md"}"#),
+        ] {
+            let req = test::TestRequest::post()
+                .uri(&format!("/{method}"))
+                .insert_header((SYNC_HEADER_NAME.clone(), header.as_str()))
+                .set_payload(body)
+                .to_request();
+            // an error is fine,as long as it is an answer
+            let resp = test::call_service(&app, req).await;
+            if !resp.status().is_success() {
+                let body: serde_json::Value = test::read_body_json(resp).await;
+                assert!(body["code"].is_string(), "{row} {method}: {body}");
+            }
+        }
+    }
+}
@@ -8,6 +8,17 @@ use crate::{
     request::SyncRequest,
 };
 
+/// Server-sent events telling connected devices that something changed,see `ChangeEvent`.
+///
+/// The session token is taken from the sync header only,a query string ends up in logs.
+pub async fn events_handler(
+    req: web::ReqData<SyncRequest<Vec<u8>>>,
+    server: web::Data<Server>,
+) -> actix_web::Result<HttpResponse> {
+    let req = req.into_inner();
+    Ok(server.into_inner().events(&req.sync_key, &req.ip).await?)
+}
+
 pub async fn sync_handler(
     req: web::ReqData<SyncRequest<Vec<u8>>>,
     method: web::Path<SyncMethod>, //(endpoint,sync_method)
@@ -18,6 +29,10 @@ pub async fn sync_handler(
     log::info!("method {:?}", method);
     let server = server.into_inner();
     match method {
+        SyncMethod::Version => {
+            let resp = server.version(req.into_output_type()).await?;
+            return Ok(resp);
+        }
         SyncMethod::HostKey => {
             let resp = server.host_key(req.into_output_type()).await?;
             return Ok(resp);
@@ -34,6 +49,37 @@ pub async fn sync_handler(
             let resp = server.download(req.into_output_type()).await?;
             return Ok(resp);
         }
-        _ => unreachable!(),
+        SyncMethod::Chunk => {
+            let resp = server.chunk(req.into_output_type()).await?;
+            return Ok(resp);
+        }
+        SyncMethod::ApplyChunk => {
+            let resp = server.apply_chunk(req.into_output_type()).await?;
+            return Ok(resp);
+        }
+        SyncMethod::Finish => {
+            let resp = server.finish(req.into_output_type()).await?;
+            return Ok(resp);
+        }
+        SyncMethod::Abort => {
+            let resp = server.abort(req.into_output_type()).await?;
+            return Ok(resp);
+        }
+        SyncMethod::History => {
+            let resp = server.history(req.into_output_type()).await?;
+            return Ok(resp);
+        }
+        SyncMethod::Restore => {
+            let resp = server.restore(req.into_output_type()).await?;
+            return Ok(resp);
+        }
+        SyncMethod::Trash => {
+            let resp = server.trash(req.into_output_type()).await?;
+            return Ok(resp);
+        }
+        SyncMethod::Undelete => {
+            let resp = server.undelete(req.into_output_type()).await?;
+            return Ok(resp);
+        }
     }
 }
@@ -1,12 +1,18 @@
 pub mod config;
 pub mod db;
 pub mod error;
+mod events;
 pub mod file_process;
+#[cfg(test)]
+mod fuzz;
 pub mod handler;
+pub mod merge;
 pub mod parse_args;
 pub mod protocol;
+mod ratelimit;
 pub mod request;
 mod server;
+mod tls;
 mod user;
 #[actix_web::main]
 async fn main() {
@@ -0,0 +1,109 @@
+// Line-based three-way merge for markdown notes edited on two devices.
+
+/// Give up on notes whose changed part is too big to diff,
+/// the upload is then kept as a conflicted copy instead.
+const MAX_CELLS: usize = 4_000_000;
+
+/// Merge `ours` (server) and `theirs` (client),both edited from `base`.
+///
+/// Return None if both sides changed the same lines differently.
+pub(crate) fn merge3(base: &str, ours: &str, theirs: &str) -> Option<String> {
+    let b = base.split_inclusive('\n').collect::<Vec<_>>();
+    let o = ours.split_inclusive('\n').collect::<Vec<_>>();
+    let t = theirs.split_inclusive('\n').collect::<Vec<_>>();
+    // base line -> the same line in ours/theirs
+    let mut in_o = vec![None; b.len()];
+    for (i, j) in matches(&b, &o)? {
+        in_o[i] = Some(j);
+    }
+    let mut in_t = vec![None; b.len()];
+    for (i, j) in matches(&b, &t)? {
+        in_t[i] = Some(j);
+    }
+
+    let mut merged = String::new();
+    let (mut bi, mut oi, mut ti) = (0, 0, 0);
+    loop {
+        // next base line kept on both sides,everything before it changed on at least one
+        let stable = (bi..b.len()).find_map(|k| match (in_o[k], in_t[k]) {
+            (Some(x), Some(y)) => Some((k, x, y)),
+            _ => None,
+        });
+        let (bk, ok, tk) = stable.unwrap_or((b.len(), o.len(), t.len()));
+        let (bc, oc, tc) = (&b[bi..bk], &o[oi..ok], &t[ti..tk]);
+        if oc == bc {
+            merged.extend(tc.iter().copied());
+        } else if tc == bc || oc == tc {
+            merged.extend(oc.iter().copied());
+        } else {
+            return None;
+        }
+        match stable {
+            Some((bk, ok, tk)) => {
+                merged.push_str(b[bk]);
+                (bi, oi, ti) = (bk + 1, ok + 1, tk + 1);
+            }
+            None => break,
+        }
+    }
+    Some(merged)
+}
+
+/// Pairs (i,j) of equal lines a[i] == b[j] along a longest common subsequence.
+fn matches(a: &[&str], b: &[&str]) -> Option<Vec<(usize, usize)>> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let ma = &a[prefix..a.len() - suffix];
+    let mb = &b[prefix..b.len() - suffix];
+    let (n, m) = (ma.len(), mb.len());
+    if n.saturating_mul(m) > MAX_CELLS {
+        return None;
+    }
+    // lcs[i * w + j] is the lcs length of ma[i..] and mb[j..]
+    let w = m + 1;
+    let mut lcs = vec![0u32; (n + 1) * w];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i * w + j] = if ma[i] == mb[j] {
+                lcs[(i + 1) * w + j + 1] + 1
+            } else {
+                lcs[(i + 1) * w + j].max(lcs[i * w + j + 1])
+            };
+        }
+    }
+    let mut pairs = (0..prefix).map(|i| (i, i)).collect::<Vec<_>>();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if ma[i] == mb[j] {
+            pairs.push((prefix + i, prefix + j));
+            i += 1;
+            j += 1;
+        } else if lcs[(i + 1) * w + j] >= lcs[i * w + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs.extend((0..suffix).map(|k| (a.len() - suffix + k, b.len() - suffix + k)));
+    Some(pairs)
+}
+
+#[test]
+fn merge_edits_on_different_lines() {
+    let base = "# todo\na\nb\nc\n";
+    let ours = "# todo\na1\nb\nc\n";
+    let theirs = "# todo\na\nb\nc\nd\n";
+    assert_eq!(
+        merge3(base, ours, theirs).as_deref(),
+        Some("# todo\na1\nb\nc\nd\n")
+    );
+    // the same edit on both sides
+    assert_eq!(merge3(base, ours, ours).as_deref(), Some(ours));
+    // different edits of the same line
+    assert_eq!(merge3(base, ours, "# todo\na2\nb\nc\n"), None);
+}
@@ -31,8 +31,23 @@ pub enum UserCommand {
         /// list all usernames extracted from db ,i.e.ankisyncd user  -l
         #[clap(short, long, action)]
         list: bool,
+        /// list deleted files of a user, i.e.obsidiansyncd user -t username
+        #[clap(short, long, value_parser, value_name("username"))]
+        trash: Option<String>,
+        /// bring deleted files back, i.e.obsidiansyncd user -u username path1 path2
+        #[clap(short, long, value_parser, num_args(2..), value_names(&["username", "path"]))]
+        undelete: Option<Vec<String>>,
+        #[command(subcommand)]
+        session: Option<SessionCommand>,
     },
 }
+#[derive(clap::Subcommand, Debug)]
+pub enum SessionCommand {
+    /// list devices logged in as a user, i.e.obsidiansyncd user sessions username
+    Sessions { username: String },
+    /// log out one device by session id from the list, i.e.obsidiansyncd user revoke 3
+    Revoke { id: i64 },
+}
 
 /// Get config from path (if specified) or default value,
 pub fn config_from_arguments(arg: &Arg) -> Result<Config, ApplicationError> {
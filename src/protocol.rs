@@ -1,8 +1,9 @@
 use std::{
     collections::HashMap,
-    fs::create_dir_all,
+    fs::{self, create_dir_all},
     path::{Path, PathBuf},
-    sync::{Arc, Mutex}, default,
+    sync::{Arc, Mutex, MutexGuard}, default,
+    time::SystemTime,
 };
 
 use actix_web::{web, HttpResponse};
@@ -11,18 +12,27 @@ use serde::{Deserialize, Serialize};
 use strum::IntoStaticStr;
 
 use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
 
 use crate::{
-    db::{fetch_users, DbManager, Meta},
+    db::{DbManager, Meta},
     error::ApplicationError,
-    file_process::{download, server_meta, upload},
+    events::{sse, Notifier},
+    file_process::{
+        abort, apply_chunk, chunk, download, finish, history, restore, server_meta, trash,
+        undelete, upload,
+    },
+    ratelimit::LoginLimiter,
     request::SyncRequest,
-    user::{compute_hash, UserError},
+    user::{login, session_user, user_list, UserError},
 };
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct HostKeyRequest {
     pub username: String,
     pub password: String,
+    /// name the session is listed under,the device id of the sync header if empty
+    #[serde(default)]
+    pub device: String,
 }
 #[derive(Debug, Deserialize, Serialize)]
 struct HostKeyResponse {
@@ -32,6 +42,14 @@ struct HostKeyResponse {
 pub(crate) struct MetaRequest {
     // pub(crate) states: Vec<FileInfo>,
     pub(crate) states: Vec<MetaInner>,
+    /// time of the client's last successful sync,in the unit of mtime.
+    /// 0 if unknown,then no conflict can be told from mtime.
+    #[serde(default)]
+    pub(crate) last_sync: i64,
+    /// `cursor` of the last MetaResponse.If present,`states` only holds the client's
+    /// own changes and the server answers with what changed after the cursor.
+    #[serde(default)]
+    pub(crate) cursor: Option<i64>,
 }
 /// state from client
 #[derive(Debug, Deserialize, Serialize, Default, Clone,Hash,PartialEq, Eq)]
@@ -43,20 +61,52 @@ pub(crate) struct FileInfo {
     // it is used when the file is to be written to server db,just create an index
     // to the original flle content if the original exist in db meta.
     pub(crate) oldpath: String,
+    /// sha256 of content in hex,empty if the sender doesn't know it
+    #[serde(default)]
+    pub(crate) hash: String,
 }
 
 impl FileInfo {
     pub(crate) fn path(&self) -> String {
         self.path.to_string()
     }
-
-    pub(crate) fn name(&self) -> String {
-        self.name.to_string()
+    /// key of the file on the server,old clients may only send name
+    pub(crate) fn key(&self) -> String {
+        if self.path.is_empty() {
+            normalize_path(&self.name)
+        } else {
+            normalize_path(&self.path)
+        }
+    }
+}
+impl From<&Meta> for FileInfo {
+    fn from(meta: &Meta) -> Self {
+        FileInfo {
+            name: meta.fname(),
+            path: meta.paths(),
+            mtime: meta.mtime(),
+            ctime: meta.ctime(),
+            oldpath: "".to_string(),
+            hash: meta.hash(),
+        }
     }
 }
+/// Vault-relative path used as key of a file,so that `Daily/notes.md`
+/// and `Projects/notes.md` are different files.
+/// `\` becomes `/`,empty and `.` segments are dropped.
+pub(crate) fn normalize_path(path: &str) -> String {
+    path.replace('\\', "/")
+        .split('/')
+        .filter(|s| !s.is_empty() && *s != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct MetaResponse {
     pub(crate) metainner: Vec<MetaInner>,
+    /// usn of the latest change the response covers,send it as cursor next time
+    #[serde(default)]
+    pub(crate) cursor: Option<i64>,
 }
 #[derive(Debug, Deserialize, Serialize, Clone,Default,Hash,PartialEq, Eq)]
 pub(crate) struct MetaInner {
@@ -74,13 +124,7 @@ impl MetaInner {
     pub(crate) fn new(action: FileAction, meta: &Meta) -> Self {
         Self {
             action,
-            fileinfo: FileInfo {
-                name: meta.fname(),
-                path: meta.paths(),
-                mtime: meta.mtime(),
-                ctime: meta.ctime(),
-                oldpath: "".to_string(),
-            },
+            fileinfo: FileInfo::from(meta),
         }
     }
 }
@@ -93,6 +137,8 @@ pub(crate) enum FileAction {
     Delete,
     Chunk,
     Modify,
+    /// changed on both sides since the client's last sync
+    Conflict,
     /// for client sending request meta .those files without file event
      #[default]
     Absent,
@@ -104,21 +150,185 @@ pub(crate) struct DownloadResponse {
 }
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct DownloadRequest {
-    pub(crate) filenames: Vec<String>,
+    /// old clients send it as filenames
+    #[serde(alias = "filenames")]
+    pub(crate) paths: Vec<String>,
 }
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct UploadRequest {
     pub(crate) files: Vec<Pfile>,
 }
+/// Files the client has to fetch again because its upload of a note was merged
+/// with edits from another device,or kept as a conflicted copy.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct UploadResponse {
+    pub(crate) files: Vec<MetaInner>,
+}
+/// Ask for files of a chunked download.
+/// Files beyond the chunk size are sent back in `remaining`,
+/// the client requests them again with the next chunk call.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ChunkRequest {
+    #[serde(alias = "filenames")]
+    pub(crate) paths: Vec<String>,
+}
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ChunkResponse {
+    pub(crate) files: Vec<Pfile>,
+    pub(crate) remaining: Vec<String>,
+}
+/// One piece of a chunked upload,kept aside and only written to meta on finish.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ApplyChunkRequest {
+    pub(crate) files: Vec<Pfile>,
+}
+/// List the revisions of a file.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct HistoryRequest {
+    pub(crate) path: String,
+}
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct HistoryResponse {
+    /// newest first
+    pub(crate) revisions: Vec<Revision>,
+}
+/// A previous version of a file.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Revision {
+    pub(crate) id: i64,
+    pub(crate) path: String,
+    pub(crate) hash: String,
+    pub(crate) ctime: i64,
+    pub(crate) mtime: i64,
+    /// when this version was replaced,in milliseconds
+    pub(crate) created: i64,
+}
+/// Make revision `revision` of `path` current again.
+/// Response is the restored file with action download.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct RestoreRequest {
+    pub(crate) path: String,
+    pub(crate) revision: i64,
+}
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct TrashResponse {
+    /// latest deletion first
+    pub(crate) files: Vec<TrashEntry>,
+}
+/// A deleted file,kept until the retention period is over.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct TrashEntry {
+    pub(crate) id: i64,
+    pub(crate) path: String,
+    pub(crate) hash: String,
+    pub(crate) ctime: i64,
+    pub(crate) mtime: i64,
+    /// device id of the client that deleted it
+    pub(crate) deleted_by: String,
+    /// in milliseconds
+    pub(crate) deleted_at: i64,
+}
+/// Bring deleted files back from trash.
+/// Response is a MetaResponse with the restored files marked download.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct UndeleteRequest {
+    pub(crate) paths: Vec<String>,
+}
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct Pfile {
     pub(crate) states: FileInfo,
-    pub(crate) content: String,
+    /// sent as fields `content` and `encoding`
+    #[serde(flatten)]
+    pub(crate) content: FileContent,
+}
+/// how `content` of a Pfile is encoded on the wire.
+/// Old clients only send text,so utf8 is the default.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum ContentEncoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+/// Raw bytes of a file,kept unchanged from upload to download.
+///
+/// Text goes over the wire as it is,anything that isn't valid utf8
+/// (images,pdf,audio) as base64.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Default)]
+#[serde(try_from = "WireContent", into = "WireContent")]
+pub(crate) struct FileContent(pub(crate) Vec<u8>);
+#[derive(Deserialize, Serialize)]
+struct WireContent {
+    content: String,
+    #[serde(default)]
+    encoding: ContentEncoding,
+}
+impl From<FileContent> for WireContent {
+    fn from(c: FileContent) -> Self {
+        match String::from_utf8(c.0) {
+            Ok(content) => Self {
+                content,
+                encoding: ContentEncoding::Utf8,
+            },
+            Err(e) => Self {
+                content: STANDARD.encode(e.into_bytes()),
+                encoding: ContentEncoding::Base64,
+            },
+        }
+    }
+}
+impl TryFrom<WireContent> for FileContent {
+    type Error = base64::DecodeError;
+    fn try_from(w: WireContent) -> Result<Self, Self::Error> {
+        match w.encoding {
+            ContentEncoding::Utf8 => Ok(Self(w.content.into_bytes())),
+            ContentEncoding::Base64 => Ok(Self(STANDARD.decode(w.content)?)),
+        }
+    }
+}
+/// Version of the sync protocol,sent by clients as `v` in sync header.
+/// Bump it when requests or responses change in a way old clients can't just ignore.
+pub(crate) const PROTOCOL_VERSION: u32 = 1;
+/// Oldest client protocol still served.Clients from before versioning send no `v`,
+/// which is 0,and are adapted to with serde defaults.
+pub(crate) const MIN_PROTOCOL_VERSION: u32 = 0;
+/// optional features a client may use,see docs/CHANGELOG.md
+const CAPABILITIES: &[&str] = &["chunk", "merge", "history", "trash", "binary", "compression"];
+
+/// Reject clients the server can't talk to with a message the user can act on.
+pub(crate) fn check_protocol_version(version: u32) -> Result<(), ApplicationError> {
+    if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Ok(());
+    }
+    let message = if version > PROTOCOL_VERSION {
+        format!("client protocol {version} is newer than {PROTOCOL_VERSION} of the server,please update the server")
+    } else {
+        format!("client protocol {version} is older than {MIN_PROTOCOL_VERSION} required by the server,please update the plugin")
+    };
+    Err(ApplicationError::UnsupportedVersion(message))
+}
+/// Sent to connected devices of a user when a change is committed,see `/events`.
+#[derive(Debug, Serialize)]
+pub(crate) struct ChangeEvent {
+    /// latest usn,pass it as cursor to meta
+    pub(crate) cursor: i64,
+    /// device id of the client that made the change,empty if unknown
+    pub(crate) device: String,
+}
+/// Answer of `version`,asked for before login.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct VersionResponse {
+    /// version of obsidiansyncd
+    pub(crate) server: String,
+    pub(crate) protocol: u32,
+    pub(crate) min_protocol: u32,
+    pub(crate) capabilities: Vec<String>,
 }
 #[derive(IntoStaticStr, Deserialize, PartialEq, Eq, Debug)]
 #[serde(rename_all = "camelCase")]
 #[strum(serialize_all = "camelCase")]
 pub enum SyncMethod {
+    Version,
     HostKey,
     Meta,
     Chunk,
@@ -127,10 +337,16 @@ pub enum SyncMethod {
     Abort,
     Upload,
     Download,
+    History,
+    Restore,
+    Trash,
+    Undelete,
 }
 
 #[async_trait]
 pub(crate) trait SyncProtocol: Send + Sync + 'static {
+    /// protocol version and capabilities,no login needed
+    async fn version(&self, req: SyncRequest<()>) -> Result<HttpResponse, ApplicationError>;
     async fn host_key(
         &self,
         req: SyncRequest<HostKeyRequest>,
@@ -147,43 +363,92 @@ pub(crate) trait SyncProtocol: Send + Sync + 'static {
         &self,
         req: SyncRequest<DownloadRequest>,
     ) -> Result<HttpResponse, ApplicationError>;
+    /// Chunked sync session.
+    ///
+    /// The first chunk or applyChunk carrying a new session key opens a session of the
+    /// device,files sent during it are only written on finish and thrown away on abort.
+    async fn chunk(&self, req: SyncRequest<ChunkRequest>)
+        -> Result<HttpResponse, ApplicationError>;
+    async fn apply_chunk(
+        &self,
+        req: SyncRequest<ApplyChunkRequest>,
+    ) -> Result<HttpResponse, ApplicationError>;
+    async fn finish(&self, req: SyncRequest<()>) -> Result<HttpResponse, ApplicationError>;
+    async fn abort(&self, req: SyncRequest<()>) -> Result<HttpResponse, ApplicationError>;
+    /// revisions of a file
+    async fn history(
+        &self,
+        req: SyncRequest<HistoryRequest>,
+    ) -> Result<HttpResponse, ApplicationError>;
+    async fn restore(
+        &self,
+        req: SyncRequest<RestoreRequest>,
+    ) -> Result<HttpResponse, ApplicationError>;
+    /// deleted files kept in trash
+    async fn trash(&self, req: SyncRequest<()>) -> Result<HttpResponse, ApplicationError>;
+    async fn undelete(
+        &self,
+        req: SyncRequest<UndeleteRequest>,
+    ) -> Result<HttpResponse, ApplicationError>;
 }
 #[async_trait]
 impl SyncProtocol for Arc<Server> {
+    async fn version(&self, _req: SyncRequest<()>) -> Result<HttpResponse, ApplicationError> {
+        Ok(HttpResponse::Ok().json(VersionResponse {
+            server: env!("CARGO_PKG_VERSION").to_string(),
+            protocol: PROTOCOL_VERSION,
+            min_protocol: MIN_PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }))
+    }
     async fn meta(&self, req: SyncRequest<MetaRequest>) -> Result<HttpResponse, ApplicationError> {
         let s = self
-            .with_authenticated_user(req, |user, req| Ok(server_meta(req.json()?, &user.db)?))
+            .with_authenticated_user(req, |user, req| {
+                server_meta(req.json()?, &req.device, &user.db)
+            })
             .await?;
-        Ok(s)
+        Ok(HttpResponse::Ok().json(s))
     }
     async fn host_key(
         &self,
         req: SyncRequest<HostKeyRequest>,
     ) -> Result<HttpResponse, ApplicationError> {
+        let (device, ip) = (req.device.to_string(), req.ip.to_string());
         let req = req.json()?;
         let username = req.username;
         let password = req.password;
-        // extract hash from User if username match,else return no such username error,
-        let users = self.users.lock().expect("mutex lock");
-        let user = users.iter().find(|(_hash, u)| u.name == username);
-        match user {
-            Some((hash, _u)) => {
-                let actual_hash = compute_hash(&username, &password, hash);
-                if actual_hash == *hash {
-                    Ok(HttpResponse::Ok().json(HostKeyResponse {
-                        key: hash.to_string(),
-                    }))
-                } else {
-                    Err(UserError::Authentication(format!(
-                        "Authentication failed for user {username}"
-                    ))
-                    .into())
+        // device name from request body,or the device id of the sync header
+        let device = if req.device.is_empty() {
+            device
+        } else {
+            req.device
+        };
+        if let Some(secs) = self.login_limiter.locked(&ip, &username) {
+            log::warn!("login refused for user {username:?} from {ip},locked out for {secs}s");
+            return Err(ApplicationError::TooManyRequests(secs));
+        }
+        let (auth_db, ttl_days) = (self.auth_db.to_string(), self.session_ttl_days);
+        let (name, addr) = (username.to_string(), ip.to_string());
+        let token =
+            web::block(move || login(&name, &password, &device, &addr, ttl_days, &auth_db))
+                .await??;
+        match token {
+            Some(key) => {
+                self.login_limiter.succeeded(&username);
+                Ok(HttpResponse::Ok().json(HostKeyResponse { key }))
+            }
+            None => {
+                // one line per failure with the client address,for fail2ban.
+                // username is quoted and escaped,so that it can't fake the address at the end
+                log::warn!("authentication failure for user {username:?} from {ip}");
+                if self.login_limiter.failed(&ip, &username) {
+                    log::warn!("login locked out for user {username:?} from {ip}");
                 }
+                Err(UserError::Authentication(format!(
+                    "Authentication failed for user {username}"
+                ))
+                .into())
             }
-            None => Err(UserError::Authentication(format!(
-                "Authentication failed for nonexistent user {username}"
-            ))
-            .into()),
         }
     }
     async fn upload(
@@ -191,81 +456,396 @@ impl SyncProtocol for Arc<Server> {
         req: SyncRequest<UploadRequest>,
     ) -> Result<HttpResponse, ApplicationError> {
         let s = self
-            .with_authenticated_user(req, |user, req| Ok(upload(req.json()?, &user.db)?))
+            .with_authenticated_user(req, |user, req| upload(&req, &user.db))
             .await?;
-        Ok(s)
+        Ok(HttpResponse::Ok().json(s))
     }
     async fn download(
         &self,
         req: SyncRequest<DownloadRequest>,
     ) -> Result<HttpResponse, ApplicationError> {
         let s = self
-            .with_authenticated_user(req, |user, req| Ok(download(req.json()?, &user.db)?))
+            .with_authenticated_user(req, |user, req| {
+                download(req.json()?, &req.device, &user.db)
+            })
             .await?;
-        Ok(s)
+        Ok(HttpResponse::Ok().json(s))
+    }
+    async fn chunk(
+        &self,
+        req: SyncRequest<ChunkRequest>,
+    ) -> Result<HttpResponse, ApplicationError> {
+        let s = self
+            .with_authenticated_user(req, |user, req| {
+                chunk(req.json()?, &req.session_key, &req.device, &user.db)
+            })
+            .await?;
+        Ok(HttpResponse::Ok().json(s))
+    }
+    async fn apply_chunk(
+        &self,
+        req: SyncRequest<ApplyChunkRequest>,
+    ) -> Result<HttpResponse, ApplicationError> {
+        let s = self
+            .with_authenticated_user(req, |user, req| apply_chunk(&req, &user.db))
+            .await?;
+        Ok(HttpResponse::Ok().json(s))
+    }
+    async fn finish(&self, req: SyncRequest<()>) -> Result<HttpResponse, ApplicationError> {
+        let s = self
+            .with_authenticated_user(req, |user, req| {
+                finish(&req.session_key, &req.device, &user.db)
+            })
+            .await?;
+        Ok(HttpResponse::Ok().json(s))
+    }
+    async fn abort(&self, req: SyncRequest<()>) -> Result<HttpResponse, ApplicationError> {
+        self.with_authenticated_user(req, |user, req| {
+            abort(&req.session_key, &req.device, &user.db)
+        })
+        .await?;
+        Ok(HttpResponse::Ok().finish())
+    }
+    async fn history(
+        &self,
+        req: SyncRequest<HistoryRequest>,
+    ) -> Result<HttpResponse, ApplicationError> {
+        let s = self
+            .with_authenticated_user(req, |user, req| history(req.json()?, &user.db))
+            .await?;
+        Ok(HttpResponse::Ok().json(s))
+    }
+    async fn restore(
+        &self,
+        req: SyncRequest<RestoreRequest>,
+    ) -> Result<HttpResponse, ApplicationError> {
+        let s = self
+            .with_authenticated_user(req, |user, req| restore(req.json()?, &user.db))
+            .await?;
+        Ok(HttpResponse::Ok().json(s))
+    }
+    async fn trash(&self, req: SyncRequest<()>) -> Result<HttpResponse, ApplicationError> {
+        let s = self
+            .with_authenticated_user(req, |user, _req| trash(&user.db))
+            .await?;
+        Ok(HttpResponse::Ok().json(s))
+    }
+    async fn undelete(
+        &self,
+        req: SyncRequest<UndeleteRequest>,
+    ) -> Result<HttpResponse, ApplicationError> {
+        let s = self
+            .with_authenticated_user(req, |user, req| undelete(req.json()?, &user.db))
+            .await?;
+        Ok(HttpResponse::Ok().json(s))
     }
 }
 struct User {
     name: String,
     folder: PathBuf,
     db: DbManager,
+    /// cursor connected devices were last told about
+    notified: i64,
 }
 
 impl User {
     fn new(name: String, folder: PathBuf) -> Result<Self, ApplicationError> {
         let db = DbManager::new(&folder)?;
-        Ok(Self { name, folder, db })
+        let notified = db.cursor()?;
+        Ok(Self {
+            name,
+            folder,
+            db,
+            notified,
+        })
+    }
+    /// Cursor to tell connected devices about,if changes were committed since last time.
+    /// Files of an open session are only staged,they count once it finishes.
+    fn committed_changes(&mut self) -> Option<i64> {
+        match self.db.cursor() {
+            Ok(cursor) if cursor > self.notified => {
+                self.notified = cursor;
+                Some(cursor)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                log::error!("failed to read cursor of user {}: {e}", self.name);
+                None
+            }
+        }
+    }
+}
+
+/// A user as kept by the server.
+///
+/// Each user has a lock of its own,so that one user's big upload doesn't hold up
+/// the sync of others.Name is outside the lock,login can look it up without waiting.
+#[derive(Clone)]
+struct SharedUser {
+    name: String,
+    user: Arc<Mutex<User>>,
+    /// devices listening for changes,outside the lock too
+    notifier: Arc<Notifier>,
+}
+
+impl SharedUser {
+    fn new(user: User) -> Self {
+        Self {
+            name: user.name.to_string(),
+            user: Arc::new(Mutex::new(user)),
+            notifier: Arc::new(Notifier::default()),
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, User> {
+        self.user.lock().expect("mutex lock")
     }
 }
 
 pub struct Server {
-    users: Mutex<HashMap<String, User>>,
+    /// user name -> user,only locked to look a user up
+    users: Mutex<HashMap<String, SharedUser>>,
+    base_folder: PathBuf,
+    auth_db: String,
+    /// session tokens expire this many days after login,0 for never
+    session_ttl_days: u32,
+    login_limiter: LoginLimiter,
+    /// modified time of auth.db when users were last read from it,
+    /// locked for the whole of a refresh
+    auth_modified: Mutex<Option<SystemTime>>,
 }
 impl Server {
-    async fn with_authenticated_user<F, I>(
-        &self,
+    /// Run `op` for the user of the request's host key.
+    ///
+    /// Sqlite work is blocking,so `op` runs on the blocking thread pool rather than
+    /// stalling the async worker and every other request on it.
+    async fn with_authenticated_user<F, I, R>(
+        self: &Arc<Self>,
         req: SyncRequest<I>,
         op: F,
-    ) -> Result<HttpResponse, ApplicationError>
+    ) -> Result<R, ApplicationError>
     where
-        F: FnOnce(&mut User, SyncRequest<I>) -> Result<HttpResponse, ApplicationError>,
+        F: FnOnce(&mut User, SyncRequest<I>) -> Result<R, ApplicationError> + Send + 'static,
+        I: Send + 'static,
+        R: Send + 'static,
     {
-        let mut users = self.users.lock().expect("mutex lock");
-        let user = match users.get_mut(&req.sync_key) {
-            Some(u) => u,
-            None => {
-                return Err(ApplicationError::InvalidHostKey(
-                    "invalid host key".to_string(),
-                ))
+        let user = self.authenticate(&req.sync_key, &req.ip).await?;
+        let device = req.device.to_string();
+        web::block(move || {
+            let mut locked = user.lock();
+            let r = op(&mut locked, req)?;
+            if let Some(cursor) = locked.committed_changes() {
+                user.notifier.notify(&ChangeEvent { cursor, device });
+            }
+            Ok(r)
+        })
+        .await?
+    }
+    /// User the session token was issued to.
+    ///
+    /// Both reading auth.db and loading users that were added since are blocking,
+    /// so they run on the blocking thread pool too.
+    async fn authenticate(
+        self: &Arc<Self>,
+        token: &str,
+        ip: &str,
+    ) -> Result<SharedUser, ApplicationError> {
+        let server = self.clone();
+        let (token, ip) = (token.to_string(), ip.to_string());
+        let name = web::block(move || {
+            server.refresh_users()?;
+            Ok::<_, ApplicationError>(session_user(&token, &ip, &server.auth_db)?)
+        })
+        .await??;
+        let user = name.and_then(|n| self.users.lock().expect("mutex lock").get(&n).cloned());
+        match user {
+            Some(u) => Ok(u),
+            None => Err(ApplicationError::InvalidHostKey(
+                "invalid or expired session token".to_string(),
+            )),
+        }
+    }
+    /// Stream of changes to the user of `token`,as server-sent events.
+    pub(crate) async fn events(
+        self: &Arc<Self>,
+        token: &str,
+        ip: &str,
+    ) -> Result<HttpResponse, ApplicationError> {
+        let user = self.authenticate(token, ip).await?;
+        log::info!("user {} listens for changes from {ip}", user.name);
+        let (server, token, ip) = (self.clone(), token.to_string(), ip.to_string());
+        let name = user.name.to_string();
+        let alive = move || {
+            let (server, token, ip) = (server.clone(), token.clone(), ip.clone());
+            let name = name.to_string();
+            async move {
+                let auth_db = server.auth_db.to_string();
+                match web::block(move || session_user(&token, &ip, &auth_db)).await {
+                    Ok(Ok(user)) => user == Some(name),
+                    // a busy auth.db doesn't end the stream,the next keep-alive asks again
+                    Ok(Err(e)) => {
+                        log::error!("{e}");
+                        true
+                    }
+                    Err(e) => {
+                        log::error!("{e}");
+                        true
+                    }
+                }
             }
         };
-        op(user, req)
+        Ok(sse(user.notifier.subscribe(), alive))
     }
 }
 impl Server {
-    pub fn new_from_db(base_folder: &Path, auth_db: &str) -> Result<Server, ApplicationError> {
-        let mut server = HashMap::new();
-        let users = fetch_users(auth_db)?;
-        let users = if let Some(users) = users {
-            for (name, hash) in users {
-                let folder = base_folder.join(&name);
-                create_dir_all(&folder)?;
-                let user = User::new(name, folder)?;
-                server.insert(hash, user);
+    /// Empty the trash of every user of files deleted more than `retention_days` ago.
+    pub(crate) fn purge_trash(&self, retention_days: u32) {
+        for user in self.user_list() {
+            let user = user.lock();
+            match user.db.purge_trash(retention_days) {
+                Ok(0) => {}
+                Ok(n) => log::info!("purged {n} files from trash of user {}", user.name),
+                Err(e) => log::error!("failed to purge trash of user {}: {e}", user.name),
             }
-            server
-        } else {
-            return Err(ApplicationError::UserError(
-                crate::user::UserError::MissingValues(
-                    "no user found on the server side".to_string(),
-                ),
-            ));
-        };
-        Ok(Server::new(users))
+        }
     }
-    fn new(users: HashMap<String, User>) -> Self {
+    /// Drop chunked sessions of clients that went away without finish or abort.
+    pub(crate) fn expire_sessions(&self) {
+        for user in self.user_list() {
+            let user = user.lock();
+            match user.db.expire_sessions() {
+                Ok(0) => {}
+                Ok(n) => log::info!("dropped {n} idle sessions of user {}", user.name),
+                Err(e) => log::error!("failed to drop idle sessions of user {}: {e}", user.name),
+            }
+        }
+    }
+    fn user_list(&self) -> Vec<SharedUser> {
+        self.users
+            .lock()
+            .expect("mutex lock")
+            .values()
+            .cloned()
+            .collect()
+    }
+    pub fn new_from_db(
+        base_folder: &Path,
+        auth_db: &str,
+        session_ttl_days: u32,
+        login_limiter: LoginLimiter,
+    ) -> Result<Server, ApplicationError> {
+        let server = Server::new(
+            base_folder.to_path_buf(),
+            auth_db.to_string(),
+            session_ttl_days,
+            login_limiter,
+        );
+        // users added later are picked up by refresh_users
+        server.refresh_users()?;
+        Ok(server)
+    }
+    fn new(
+        base_folder: PathBuf,
+        auth_db: String,
+        session_ttl_days: u32,
+        login_limiter: LoginLimiter,
+    ) -> Self {
         Self {
-            users: Mutex::new(users),
+            users: Mutex::new(HashMap::new()),
+            base_folder,
+            auth_db,
+            session_ttl_days,
+            login_limiter,
+            auth_modified: Mutex::new(None),
+        }
+    }
+    /// Pick up users added or deleted with the cli.
+    ///
+    /// auth.db is only read again when it has been modified since last time.
+    /// Logins write sessions to it as well,then only the list of names is read,
+    /// users still there keep their db and only new ones are opened.
+    /// A user whose db fails to open is left out and tried again on the next change,
+    /// the other users go on syncing.
+    fn refresh_users(&self) -> Result<(), ApplicationError> {
+        // held throughout,so that two refreshes don't both open a new user
+        let mut last_modified = self.auth_modified.lock().expect("mutex lock");
+        let modified = fs::metadata(&self.auth_db)?.modified()?;
+        if *last_modified == Some(modified) {
+            return Ok(());
+        }
+        let mut known = self.users.lock().expect("mutex lock").clone();
+        let mut users = HashMap::new();
+        for name in user_list(&self.auth_db)?.unwrap_or_default() {
+            let user = match known.remove(&name) {
+                Some(u) => u,
+                None => match self.load_user(&name) {
+                    Ok(u) => u,
+                    Err(e) => {
+                        log::error!("failed to load user {name}: {e}");
+                        continue;
+                    }
+                },
+            };
+            users.insert(user.name.to_string(), user);
+        }
+        for name in known.keys() {
+            log::info!("drop deleted user {name}");
         }
+        *self.users.lock().expect("mutex lock") = users;
+        *last_modified = Some(modified);
+        Ok(())
+    }
+    fn load_user(&self, name: &str) -> Result<SharedUser, ApplicationError> {
+        let folder = self.base_folder.join(name);
+        create_dir_all(&folder)?;
+        log::info!("load user {name}");
+        Ok(SharedUser::new(User::new(name.to_string(), folder)?))
+    }
+}
+
+#[test]
+fn binary_content_round_trip() {
+    let bytes = vec![0x89, b'P', b'N', b'G', 0, 0xff, 0xfe];
+    let pfile = Pfile {
+        states: FileInfo::default(),
+        content: FileContent(bytes.clone()),
+    };
+    let s = serde_json::to_string(&pfile).unwrap();
+    assert!(s.contains("\"encoding\":\"base64\""));
+    let back: Pfile = serde_json::from_str(&s).unwrap();
+    assert_eq!(back.content.0, bytes);
+    // old clients send text without encoding
+    let old = r#"{"states":{"name":"a.md","path":"a.md","mtime":1,"ctime":1,"oldpath":""},"content":"hi"}"#;
+    let pfile: Pfile = serde_json::from_str(old).unwrap();
+    assert_eq!(pfile.content.0, b"hi");
+}
+
+#[test]
+fn normalized_path_key() {
+    assert_eq!(normalize_path("Daily/notes.md"), "Daily/notes.md");
+    assert_eq!(normalize_path("./Daily//notes.md"), "Daily/notes.md");
+    assert_eq!(normalize_path("/Daily\\notes.md"), "Daily/notes.md");
+    assert_ne!(normalize_path("Daily/notes.md"), normalize_path("Projects/notes.md"));
+}
+
+#[test]
+fn skip_user_whose_db_fails_to_open() {
+    use crate::{config::ConfigLogin, user::{add_user, create_auth_db}};
+
+    let dir = tempfile::tempdir().unwrap();
+    let auth_db = dir.path().join("auth.db");
+    create_auth_db(&auth_db).unwrap();
+    for name in ["bob", "eve"] {
+        add_user(&[name.to_string(), "pw".to_string()], &auth_db).unwrap();
     }
+    fs::write(dir.path().join("collections/eve/obsidian.db"), "not a database").unwrap();
+    let server = Server::new_from_db(
+        &dir.path().join("collections"),
+        auth_db.to_str().unwrap(),
+        0,
+        LoginLimiter::new(&ConfigLogin::default()),
+    )
+    .unwrap();
+    let users = server.users.lock().unwrap();
+    assert!(users.contains_key("bob"));
+    assert!(!users.contains_key("eve"));
 }
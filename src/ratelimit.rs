@@ -0,0 +1,120 @@
+// Throttle failed logins,so that passwords can't be guessed as fast as a client can send them.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::ConfigLogin;
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    /// start of the window failures are counted in
+    since: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Failed logins counted per ip and per username.
+///
+/// Once either reaches its limit within the window,logins from that ip or for that user
+/// are refused for the lockout time,whether the password is right or not.
+pub struct LoginLimiter {
+    max_per_ip: u32,
+    max_per_user: u32,
+    window: Duration,
+    lockout: Duration,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl LoginLimiter {
+    pub fn new(conf: &ConfigLogin) -> Self {
+        Self {
+            max_per_ip: conf.max_failures_per_ip,
+            max_per_user: conf.max_failures_per_user,
+            window: Duration::from_secs(conf.window_secs),
+            lockout: Duration::from_secs(conf.lockout_secs),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Seconds left of the lockout of ip or username,if any.
+    pub(crate) fn locked(&self, ip: &str, username: &str) -> Option<u64> {
+        let now = Instant::now();
+        let failures = self.failures.lock().expect("mutex lock");
+        [ip_key(ip), user_key(username)]
+            .iter()
+            .filter_map(|k| failures.get(k)?.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| (until - now).as_secs() + 1)
+            .max()
+    }
+    /// Count a failed login,return true if it locks the ip or username out.
+    pub(crate) fn failed(&self, ip: &str, username: &str) -> bool {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().expect("mutex lock");
+        // forget what is neither locked nor within the window
+        failures.retain(|_, f| {
+            now.duration_since(f.since) < self.window || f.locked_until.is_some_and(|u| u > now)
+        });
+        let mut locked = false;
+        for (key, max) in [(ip_key(ip), self.max_per_ip), (user_key(username), self.max_per_user)] {
+            if max == 0 {
+                continue;
+            }
+            let f = failures.entry(key).or_insert(Failures {
+                count: 0,
+                since: now,
+                locked_until: None,
+            });
+            if f.locked_until.is_some_and(|u| u <= now) {
+                // lockout is over,start counting again
+                *f = Failures {
+                    count: 0,
+                    since: now,
+                    locked_until: None,
+                };
+            }
+            f.count += 1;
+            if f.count >= max && f.locked_until.is_none() {
+                f.locked_until = Some(now + self.lockout);
+                locked = true;
+            }
+        }
+        locked
+    }
+    /// A successful login clears failures of the username,not those of the ip.
+    pub(crate) fn succeeded(&self, username: &str) {
+        self.failures
+            .lock()
+            .expect("mutex lock")
+            .remove(&user_key(username));
+    }
+}
+
+fn ip_key(ip: &str) -> String {
+    format!("ip:{ip}")
+}
+fn user_key(username: &str) -> String {
+    format!("user:{username}")
+}
+
+#[test]
+fn lock_out_after_failures() {
+    let limiter = LoginLimiter::new(&ConfigLogin {
+        max_failures_per_ip: 4,
+        max_failures_per_user: 2,
+        window_secs: 60,
+        lockout_secs: 60,
+    });
+    assert!(!limiter.failed("1.1.1.1", "bob"));
+    assert_eq!(limiter.locked("1.1.1.1", "bob"), None);
+    limiter.succeeded("bob");
+    assert!(!limiter.failed("1.1.1.1", "bob"));
+    assert!(limiter.failed("1.1.1.1", "bob"));
+    assert!(limiter.locked("2.2.2.2", "bob").is_some());
+    assert_eq!(limiter.locked("1.1.1.1", "alice"), None);
+    // the ip reaches its limit guessing another user
+    assert!(limiter.failed("1.1.1.1", "alice"));
+    assert!(limiter.locked("1.1.1.1", "carol").is_some());
+}
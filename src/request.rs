@@ -5,12 +5,20 @@ use actix_web::{
     dev::{self, Service, ServiceRequest, ServiceResponse, Transform},
     web, Error, HttpMessage,
 };
+use actix_web::http::header;
 use async_std::io::WriteExt;
 use async_std::stream::StreamExt;
 use futures_util::{future::LocalBoxFuture, TryStreamExt};
-use serde::de::DeserializeOwned;
+use serde::de::{DeserializeOwned, DeserializeSeed};
 use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigLimits;
+use crate::error::ApplicationError;
+use crate::protocol::check_protocol_version;
+use std::fs::File;
+use std::io::{self, BufReader, Seek, SeekFrom};
 use std::marker::PhantomData;
+use std::sync::Arc;
 use std::{
     future::{ready, Ready},
     rc::Rc,
@@ -20,17 +28,79 @@ pub static SYNC_HEADER_NAME: HeaderName = HeaderName::from_static("obsidian-sync
 pub struct SyncHeader {
     #[serde(rename = "k")]
     pub sync_key: String,
-    // #[serde(rename = "s")]
-    // pub session_key: String,
+    /// Key of the chunked sync session, only sent by chunk/applyChunk/finish/abort.
+    #[serde(rename = "s", default)]
+    pub session_key: String,
+    /// Stable id of the client device,lets the server merge concurrent edits.
+    #[serde(rename = "d", default)]
+    pub device: String,
+    /// Protocol version of the client,0 if it is older than versioning.
+    #[serde(rename = "v", default)]
+    pub version: u32,
+}
+/// Request body,kept in memory if small,otherwise in a temp file that is deleted on drop.
+#[derive(Clone)]
+pub enum Body {
+    Memory(Vec<u8>),
+    Spooled(Arc<File>),
+}
+impl Body {
+    /// Read the body up to `limit` bytes,anything above `spool` bytes goes to a temp file.
+    async fn read(
+        mut body_stream: actix_web::dev::Payload,
+        limit: u64,
+        spool: u64,
+    ) -> Result<Body, ApplicationError> {
+        let mut body = web::BytesMut::new();
+        let mut file: Option<(File, async_std::fs::File)> = None;
+        let mut size = 0u64;
+        while let Some(chunk) = body_stream.next().await {
+            let chunk = chunk.map_err(|e| ApplicationError::IO(io::Error::other(e)))?;
+            size += chunk.len() as u64;
+            // limit max size of payload
+            if size > limit {
+                return Err(ApplicationError::PayloadTooLarge(format!(
+                    "request body is over {limit} bytes"
+                )));
+            }
+            if file.is_none() && size > spool {
+                let f = tempfile::tempfile()?;
+                let w = async_std::fs::File::from(f.try_clone()?);
+                file = Some((f, w));
+            }
+            match file.as_mut() {
+                Some((_, w)) => {
+                    if !body.is_empty() {
+                        w.write_all(&body.split()).await?;
+                    }
+                    w.write_all(&chunk).await?;
+                }
+                None => body.extend_from_slice(&chunk),
+            }
+        }
+        match file {
+            Some((f, mut w)) => {
+                w.flush().await?;
+                Ok(Body::Spooled(Arc::new(f)))
+            }
+            None => Ok(Body::Memory(body.to_vec())),
+        }
+    }
 }
 // define a SyncRequest to hold both header and body
 #[derive(Clone)]
 pub struct SyncRequest<T> {
-    pub data: Vec<u8>,
+    pub data: Body,
     pub json_output_type: PhantomData<T>,
     /// Non-empty on every non-login request.
-    /// It is actually host key,namely hash
+    /// It is the random session token handed out by hostKey,not a password hash
     pub sync_key: String,
+    /// Empty unless the request belongs to a chunked sync session.
+    pub session_key: String,
+    /// Empty if the client doesn't send one.
+    pub device: String,
+    /// address of the client,empty if unknown
+    pub ip: String,
 }
 
 impl<T> SyncRequest<T>
@@ -39,43 +109,33 @@ where
 {
     pub(super) async fn from_header_and_stream(
         sync_header: SyncHeader,
-        mut body_stream: actix_web::dev::Payload,
-    ) -> Result<SyncRequest<T>, actix_web::Error> {
-        let mut body = web::BytesMut::new();
-        while let Some(chunk) = body_stream.next().await {
-            let chunk = chunk?;
-            // // limit max size of in-memory payload
-            // if (body.len() + chunk.len()) > MAX_SIZE {
-            //     return Err(error::ErrorBadRequest("overflow"));
-            // }
-            body.extend_from_slice(&chunk);
-        }
-
+        body_stream: actix_web::dev::Payload,
+        limit: u64,
+        spool: u64,
+    ) -> Result<SyncRequest<T>, ApplicationError> {
         Ok(SyncRequest {
-            data: body.to_vec(),
+            data: Body::read(body_stream, limit, spool).await?,
             json_output_type: std::marker::PhantomData,
             sync_key: sync_header.sync_key,
+            session_key: sync_header.session_key,
+            device: sync_header.device,
+            ip: String::new(),
         })
     }
     // with our syncheader being present
     pub(super) async fn from_stream(
-        mut body_stream: actix_web::dev::Payload,
-    ) -> Result<SyncRequest<T>, actix_web::Error> {
+        body_stream: actix_web::dev::Payload,
+        limit: u64,
+        spool: u64,
+    ) -> Result<SyncRequest<T>, ApplicationError> {
         let host_key = String::new();
-        let mut body = web::BytesMut::new();
-        while let Some(chunk) = body_stream.next().await {
-            let chunk = chunk?;
-            // // limit max size of in-memory payload
-            // if (body.len() + chunk.len()) > MAX_SIZE {
-            //     return Err(error::ErrorBadRequest("overflow"));
-            // }
-            body.extend_from_slice(&chunk);
-        }
-
         Ok(SyncRequest {
-            data: body.to_vec(),
+            data: Body::read(body_stream, limit, spool).await?,
             json_output_type: std::marker::PhantomData,
             sync_key: host_key,
+            session_key: String::new(),
+            device: String::new(),
+            ip: String::new(),
         })
     }
     /// Given a generic Self<Vec<u8>>, infer the actual type based on context.
@@ -84,16 +144,51 @@ where
             data: self.data,
             json_output_type: PhantomData,
             sync_key: self.sync_key,
+            session_key: self.session_key,
+            device: self.device,
+            ip: self.ip,
         }
     }
+    /// Spooled bodies are parsed straight from the file,call it on the blocking thread pool.
     pub fn json(&self) -> Result<T, serde_json::Error> {
-        serde_json::from_slice(&self.data)
+        match &self.data {
+            Body::Memory(data) => serde_json::from_slice(data),
+            Body::Spooled(file) => {
+                let mut file = file.as_ref();
+                file.seek(SeekFrom::Start(0)).map_err(serde_json::Error::io)?;
+                serde_json::from_reader(BufReader::new(file))
+            }
+        }
+    }
+    /// Like `json`,but parsed by `seed`,which can deal with the body piece by piece
+    /// instead of building all of `T` in memory.
+    pub fn json_seed<S, V>(&self, seed: S) -> Result<V, serde_json::Error>
+    where
+        S: for<'de> DeserializeSeed<'de, Value = V>,
+    {
+        match &self.data {
+            Body::Memory(data) => {
+                let mut de = serde_json::Deserializer::from_slice(data);
+                let value = seed.deserialize(&mut de)?;
+                de.end()?;
+                Ok(value)
+            }
+            Body::Spooled(file) => {
+                let mut file = file.as_ref();
+                file.seek(SeekFrom::Start(0)).map_err(serde_json::Error::io)?;
+                let mut de = serde_json::Deserializer::from_reader(BufReader::new(file));
+                let value = seed.deserialize(&mut de)?;
+                de.end()?;
+                Ok(value)
+            }
+        }
     }
 }
 
 #[doc(hidden)]
 pub struct SyncRequestWrapperService<S> {
     service: Rc<S>,
+    limits: Rc<ConfigLimits>,
 }
 impl<S, B> Service<ServiceRequest> for SyncRequestWrapperService<S>
 where
@@ -116,6 +211,7 @@ where
 
     fn call(&self, mut req: ServiceRequest) -> Self::Future {
         let service = self.service.clone();
+        let limits = self.limits.clone();
         Box::pin(async move {
             // let r:anki::sync::media::begin::SyncBeginQuery=serde_json::from_str( req.query_string()).unwrap();
             // let headers = req.headers();
@@ -123,35 +219,68 @@ where
             // let (req,pl)=req.into_parts();
             let headers = req.headers();
             let ip = req.peer_addr();
+            // path is /{mehod}
+            let method = req.path().trim_start_matches('/').to_string();
+            let limit = limits.limit_of(&method);
+            let spool = limits.spool_bytes();
+            // refuse before reading anything if the client says it is too big
+            let length = headers
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok());
+            if length.is_some_and(|l| l > limit) {
+                return Err(ApplicationError::PayloadTooLarge(format!(
+                    "request body of {method} is over {limit} bytes"
+                ))
+                .into());
+            }
 
             // construct struct SyncHeader.
             let sync_header_value = headers.get(&SYNC_HEADER_NAME);
             // let pl = req.take_payload();
-            let sync_request = match sync_header_value {
+            let mut sync_request = match sync_header_value {
                 Some(sync_headers) => {
                     // If SYNC_HEADER_NAME is present,
                     // need to check if it is a str
-                    let sync_header: SyncHeader =
-                        serde_json::from_str(sync_headers.to_str().ok().unwrap())?;
+                    let sync_headers = sync_headers.to_str().map_err(|_| {
+                        ApplicationError::InvalidHeader(format!(
+                            "{SYNC_HEADER_NAME} is not visible ascii"
+                        ))
+                    })?;
+                    let sync_header: SyncHeader = serde_json::from_str(sync_headers)
+                        .map_err(|e| ApplicationError::InvalidHeader(e.to_string()))?;
+                    check_protocol_version(sync_header.version)?;
                     // let pl = req.take_payload();
                     let sr: SyncRequest<Vec<u8>> =
-                        SyncRequest::from_header_and_stream(sync_header, pl).await?;
+                        SyncRequest::from_header_and_stream(sync_header, pl, limit, spool).await?;
                     sr
                 }
                 None => {
                     // If SYNC_HEADER_NAME is absent,it happens to host_key
-                    let sr = SyncRequest::from_stream(pl).await?;
+                    let sr = SyncRequest::from_stream(pl, limit, spool).await?;
                     sr
                 }
             };
+            sync_request.ip = ip.map(|a| a.ip().to_string()).unwrap_or_default();
             req.extensions_mut().insert(sync_request);
             let res = service.call(req).await?;
             Ok(res)
         })
     }
 }
+/// Reads the sync header and body of every request into a `SyncRequest`,
+/// refusing bodies over the limit of the method.
 #[derive(Clone, Debug)]
-pub struct SyncRequestWrapper;
+pub struct SyncRequestWrapper {
+    limits: Rc<ConfigLimits>,
+}
+impl SyncRequestWrapper {
+    pub fn new(limits: ConfigLimits) -> Self {
+        Self {
+            limits: Rc::new(limits),
+        }
+    }
+}
 impl<S: 'static, B> Transform<S, ServiceRequest> for SyncRequestWrapper
 where
     S::Future: 'static,
@@ -167,6 +296,7 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(SyncRequestWrapperService {
             service: Rc::new(service),
+            limits: self.limits.clone(),
         }))
     }
 }
@@ -1,12 +1,15 @@
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::error::ApplicationError;
-use crate::handler::sync_handler;
-use crate::user::create_auth_db;
+use crate::handler::{events_handler, sync_handler};
+use crate::ratelimit::LoginLimiter;
+use crate::tls::{server_config, CertResolver};
+use crate::user::{bootstrap_account, create_auth_db};
 use crate::{config::Config, protocol::Server};
 use crate::{parse_args, request};
-use actix_web::{middleware, web, App, HttpServer};
+use actix_web::{middleware, rt, web, App, HttpServer};
 use clap::Parser;
 pub async fn run() -> Result<(), ()> {
     let matches = parse_args::Arg::parse();
@@ -46,27 +49,103 @@ pub async fn server(config: &Config) -> std::result::Result<(), ApplicationError
     let root = config.data_root_path();
     let base_folder = Path::new(&root);
     let auth_db = config.auth_db_path();
-    let server = match Server::new_from_db(base_folder, &auth_db) {
+    if let Err(e) = bootstrap_account(config.account(), &auth_db) {
+        return Err(ApplicationError::LaunchServer(e.to_string()));
+    }
+    let server = match Server::new_from_db(
+        base_folder,
+        &auth_db,
+        config.session_ttl_days(),
+        LoginLimiter::new(config.login()),
+    ) {
         Ok(s) => s,
         Err(e) => return Err(ApplicationError::LaunchServer(e.to_string())),
     };
     // Create some global state prior to building the server
     let server = web::Data::new(server);
-    log::info!("listening on {}", config.listen_on());
-    HttpServer::new(move || {
+    // empty expired trash and drop idle sessions now and then every hour
+    let retention_days = config.trash_retention_days();
+    let purge = server.clone();
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            let purge = purge.clone();
+            let cleanup = move || {
+                purge.purge_trash(retention_days);
+                purge.expire_sessions();
+            };
+            if let Err(e) = web::block(cleanup).await {
+                log::error!("{e}");
+            }
+        }
+    });
+    let tls = config.tls();
+    let resolver = if tls.ssl_enable {
+        let resolver = Arc::new(CertResolver::new(&tls.cert_file, &tls.key_file)?);
+        if tls.reload_on_sighup {
+            reload_on_sighup(resolver.clone());
+        }
+        Some(resolver)
+    } else {
+        None
+    };
+    if resolver.is_none() && !tls.http_enable {
+        return Err(ApplicationError::LaunchServer(
+            "both http and https are disabled".to_string(),
+        ));
+    }
+    let limits = config.limits().clone();
+    let mut http_server = HttpServer::new(move || {
         App::new()
             .app_data(server.clone())
             // .service(welcome)
             // .service(favicon)
+            .service(web::resource("/events").route(web::get().to(events_handler)))
             .service(web::resource("/{mehod}").to(sync_handler))
-            .wrap(request::SyncRequestWrapper)
-            .wrap(middleware::Logger::default())
-    })
-    .bind(config.listen_on())
-    .expect("Failed to bind with rustls.")
-    .run()
-    .await
-    .expect("server build error");
+            .wrap(request::SyncRequestWrapper::new(limits.clone()))
+            .wrap(middleware::Compress::default())
+            .wrap(log_format())
+    });
+    if tls.http_enable || resolver.is_none() {
+        log::info!("listening on http://{}", config.listen_on());
+        http_server = http_server.bind(config.listen_on())?;
+    }
+    if let Some(resolver) = resolver {
+        log::info!("listening on https://{}", config.tls_listen_on());
+        http_server = http_server.bind_rustls(config.tls_listen_on(), server_config(resolver))?;
+    }
+    http_server.run().await?;
 
     Ok(())
 }
+/// Access log like the default one,but with the path in place of the request line,
+/// so that nothing of a query string gets into logs.
+fn log_format() -> middleware::Logger {
+    middleware::Logger::new(r#"%a "%{method}xi %U" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+        .custom_request_replace("method", |req| req.method().to_string())
+}
+/// Swap in renewed certificates without a restart.
+#[cfg(unix)]
+fn reload_on_sighup(resolver: Arc<CertResolver>) {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+    rt::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("failed to listen for SIGHUP: {e}");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            match resolver.reload() {
+                Ok(()) => log::info!("reloaded tls certificate"),
+                Err(e) => log::error!("failed to reload tls certificate: {e}"),
+            }
+        }
+    });
+}
+#[cfg(not(unix))]
+fn reload_on_sighup(_resolver: Arc<CertResolver>) {
+    log::warn!("reload_on_sighup is only supported on unix");
+}
@@ -0,0 +1,82 @@
+// TLS with certificates from the config file,so that clients can sync over https
+// without a reverse proxy.
+
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{any_supported_type, CertifiedKey},
+    Certificate, PrivateKey, ServerConfig,
+};
+use rustls_pemfile::Item;
+
+use crate::error::ApplicationError;
+
+/// Hands out the current certificate,which can be read again from disk
+/// while the server is running.
+pub struct CertResolver {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn new(cert_file: &str, key_file: &str) -> Result<Self, ApplicationError> {
+        let (cert_file, key_file) = (PathBuf::from(cert_file), PathBuf::from(key_file));
+        let key = load_certified_key(&cert_file, &key_file)?;
+        Ok(Self {
+            cert_file,
+            key_file,
+            key: RwLock::new(Arc::new(key)),
+        })
+    }
+    /// Read cert and key files again,the old certificate stays in use if that fails.
+    pub fn reload(&self) -> Result<(), ApplicationError> {
+        let key = load_certified_key(&self.cert_file, &self.key_file)?;
+        *self.key.write().expect("rwlock") = Arc::new(key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().expect("rwlock").clone())
+    }
+}
+
+pub fn server_config(resolver: Arc<CertResolver>) -> ServerConfig {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver)
+}
+
+/// certificate chain and private key from PEM files
+fn load_certified_key(cert_file: &Path, key_file: &Path) -> Result<CertifiedKey, ApplicationError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_file)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(ApplicationError::Tls(format!(
+            "no certificate found in {}",
+            cert_file.display()
+        )));
+    }
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key_file)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(k) | Item::PKCS8Key(k) | Item::ECKey(k) => Some(PrivateKey(k)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            ApplicationError::Tls(format!("no private key found in {}", key_file.display()))
+        })?;
+    let key = any_supported_type(&key).map_err(|e| ApplicationError::Tls(e.to_string()))?;
+    Ok(CertifiedKey::new(certs, key))
+}
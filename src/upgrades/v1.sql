@@ -0,0 +1,8 @@
+-- content used to be TEXT,keep bytes as they are from now on
+ALTER TABLE content RENAME TO content_old;
+CREATE TABLE content (
+    id INTEGER PRIMARY KEY,
+    content BLOB NOT NULL
+);
+INSERT INTO content (id, content) SELECT id, CAST(content AS BLOB) FROM content_old;
+DROP TABLE content_old;
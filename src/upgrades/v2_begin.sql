@@ -0,0 +1,20 @@
+-- content keyed by sha256,meta.indexs holds the hash
+ALTER TABLE content RENAME TO content_old;
+CREATE TABLE content (
+    hash TEXT PRIMARY KEY,
+    content BLOB NOT NULL
+);
+ALTER TABLE meta RENAME TO meta_old;
+CREATE TABLE meta (
+    id INTEGER PRIMARY KEY ,
+    fname TEXT NOT NULL,
+    indexs TEXT NOT NULL,
+    paths TEXT NOT NULL,
+    states TEXT NOT NULL,
+    ctime INTEGER NOT NULL,
+    mtime INTEGER NOT NULL
+);
+CREATE TEMP TABLE id_hash (
+    id INTEGER PRIMARY KEY,
+    hash TEXT NOT NULL
+);
@@ -0,0 +1,7 @@
+-- records whose content is missing could never be downloaded,drop them
+INSERT INTO meta (id, fname, indexs, paths, states, ctime, mtime)
+SELECT m.id, m.fname, h.hash, m.paths, m.states, m.ctime, m.mtime
+FROM meta_old m JOIN id_hash h ON h.id = m.indexs;
+DROP TABLE meta_old;
+DROP TABLE content_old;
+DROP TABLE id_hash;
@@ -0,0 +1,10 @@
+-- repeated uploads used to add a record per upload,keep the newest one per path.
+-- Old clients sent only a name,that is the path of the file as in FileInfo::key
+UPDATE meta SET paths = fname WHERE paths = '';
+DELETE FROM meta WHERE id NOT IN (
+    SELECT id FROM (
+        SELECT id, ROW_NUMBER() OVER (PARTITION BY paths ORDER BY mtime DESC, id DESC) AS rn
+        FROM meta
+    ) WHERE rn = 1
+);
+CREATE UNIQUE INDEX IF NOT EXISTS meta_paths ON meta (paths);
@@ -0,0 +1,9 @@
+-- paths that only differ in separators are the same file now,keep the newest one
+DELETE FROM meta WHERE id NOT IN (
+    SELECT id FROM (
+        SELECT id, ROW_NUMBER() OVER (PARTITION BY pathkey ORDER BY mtime DESC, id DESC) AS rn
+        FROM meta
+    ) WHERE rn = 1
+);
+DROP INDEX IF EXISTS meta_paths;
+CREATE UNIQUE INDEX IF NOT EXISTS meta_pathkey ON meta (pathkey);
@@ -0,0 +1,8 @@
+-- change sequence of meta,existing records get their id so they are all distinct
+ALTER TABLE meta ADD COLUMN usn INTEGER NOT NULL DEFAULT 0;
+UPDATE meta SET usn = id;
+CREATE TABLE IF NOT EXISTS usn_counter (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    next INTEGER NOT NULL
+);
+INSERT INTO usn_counter (id, next) SELECT 0, COALESCE(MAX(id), 0) + 1 FROM meta;
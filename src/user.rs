@@ -1,11 +1,17 @@
-use crate::parse_args::UserCommand;
+use crate::config::Account;
+use crate::db::{now_millis, DbManager};
+use crate::parse_args::{SessionCommand, UserCommand};
+use crate::protocol::UndeleteRequest;
 
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
 use rand::{rngs::OsRng, RngCore};
-use rusqlite::Connection;
+use rusqlite::{params, Connection, OptionalExtension};
 use sha2::{Digest, Sha256};
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -29,23 +35,18 @@ impl From<(rusqlite::Connection, rusqlite::Error)> for UserError {
     }
 }
 
-fn create_salt() -> String {
-    // create salt
-    let mut key = [0u8; 8];
-    OsRng.fill_bytes(&mut key);
-    hex::encode(key)
-}
 fn set_password_for_user<P: AsRef<Path>>(
     username: &str,
     new_password: &str,
     dbpath: P,
 ) -> Result<(), UserError> {
     if user_exists(username, &dbpath)? {
-        let salt = create_salt();
-        let hash = create_pass_hash(username, new_password, &salt);
+        let hash = create_pass_hash(new_password)?;
         let sql = "UPDATE auth SET hash=? WHERE username=?";
         let conn = Connection::open(dbpath)?;
         conn.execute(sql, [hash.as_str(), username])?;
+        // log out every device with the old password
+        conn.execute("DELETE FROM sessions WHERE username=?", [username])?;
         conn.close()?;
     }
 
@@ -63,8 +64,7 @@ fn add_user_to_auth_db<P: AsRef<Path>>(
     password: &str,
     dbpath: P,
 ) -> Result<(), UserError> {
-    let salt = create_salt();
-    let pass_hash = create_pass_hash(username, password, &salt);
+    let pass_hash = create_pass_hash(password)?;
     let sql = "INSERT INTO auth VALUES (?, ?)";
     let conn = Connection::open(&dbpath)?;
     conn.execute(sql, [username, pass_hash.as_str()])?;
@@ -92,18 +92,159 @@ fn del_user<P: AsRef<Path>>(username: &str, dbpath: P) -> Result<(), UserError>
     let sql = "DELETE FROM auth WHERE username=?";
     let conn = Connection::open(dbpath)?;
     conn.execute(sql, [username])?;
+    conn.execute("DELETE FROM sessions WHERE username=?", [username])?;
     conn.close()?;
     Ok(())
 }
+/// open the collection db of a user,which is next to auth db
+fn user_db<P: AsRef<Path>>(username: &str, dbpath: P) -> Result<DbManager, UserError> {
+    let user_dir = match dbpath.as_ref().parent() {
+        Some(p) => p.join("collections").join(username),
+        None => return Err(UserError::PathNotFound),
+    };
+    if !user_dir.exists() {
+        return Err(UserError::MissingValues(format!("no such user {username}")));
+    }
+    Ok(DbManager::new(&user_dir)?)
+}
 pub fn create_auth_db<P: AsRef<Path>>(p: P) -> Result<(), UserError> {
     let sql = "CREATE TABLE IF NOT EXISTS auth
-(username VARCHAR PRIMARY KEY, hash VARCHAR)";
+(username VARCHAR PRIMARY KEY, hash VARCHAR);
+CREATE TABLE IF NOT EXISTS sessions
+(id INTEGER PRIMARY KEY, token VARCHAR UNIQUE NOT NULL, username VARCHAR NOT NULL,
+device VARCHAR NOT NULL, created INTEGER NOT NULL, last_used INTEGER NOT NULL, expires INTEGER,
+last_ip VARCHAR NOT NULL DEFAULT '');";
     let conn = Connection::open(p)?;
-    conn.execute(sql, [])?;
+    conn.execute_batch(sql)?;
     conn.close()?;
 
     Ok(())
 }
+/// only the sha256 of a session token is kept,so reading auth.db doesn't give one away
+fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token))
+}
+/// Check the password and issue a new session token for `device`.
+///
+/// None if there is no such user or the password is wrong.
+pub fn login<P: AsRef<Path>>(
+    username: &str,
+    password: &str,
+    device: &str,
+    ip: &str,
+    ttl_days: u32,
+    dbpath: P,
+) -> Result<Option<String>, UserError> {
+    let conn = Connection::open(dbpath)?;
+    let hash: Option<String> = conn
+        .query_row("SELECT hash FROM auth WHERE username=?", [username], |r| {
+            r.get(0)
+        })
+        .optional()?;
+    let hash = match hash {
+        Some(hash) if verify_pass_hash(username, password, &hash) => hash,
+        Some(_) => return Ok(None),
+        None => {
+            // as slow as a wrong password,so that the time taken doesn't tell which users exist
+            verify_pass_hash(username, password, dummy_pass_hash());
+            return Ok(None);
+        }
+    };
+    if !hash.starts_with("$argon2") {
+        // password is at hand only now,replace the old sha256 hash
+        let sql = "UPDATE auth SET hash=? WHERE username=?";
+        conn.execute(sql, [create_pass_hash(password)?.as_str(), username])?;
+        log::info!("upgraded password hash of user {username}");
+    }
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    let token = hex::encode(key);
+    let now = now_millis();
+    let expires = (ttl_days > 0).then(|| now + i64::from(ttl_days) * 24 * 3600 * 1000);
+    conn.execute(
+        "DELETE FROM sessions WHERE expires IS NOT NULL AND expires < ?",
+        [now],
+    )?;
+    conn.execute(
+        "INSERT INTO sessions (token, username, device, created, last_used, expires, last_ip)
+VALUES (?, ?, ?, ?, ?, ?, ?)",
+        params![token_hash(&token), username, device, now, now, expires, ip],
+    )?;
+    Ok(Some(token))
+}
+/// Name of the user `token` was issued to,None if unknown or expired.
+pub fn session_user<P: AsRef<Path>>(
+    token: &str,
+    ip: &str,
+    dbpath: P,
+) -> Result<Option<String>, UserError> {
+    let conn = Connection::open(dbpath)?;
+    let now = now_millis();
+    let hash = token_hash(token);
+    let username = conn
+        .query_row(
+            "SELECT username FROM sessions WHERE token=? AND (expires IS NULL OR expires > ?)",
+            params![hash, now],
+            |r| r.get(0),
+        )
+        .optional()?;
+    if username.is_some() {
+        // no need to write on every request
+        conn.execute(
+            "UPDATE sessions SET last_used=?1, last_ip=?2
+WHERE token=?3 AND (last_used < ?1 - 60000 OR last_ip != ?2)",
+            params![now, ip, hash],
+        )?;
+    }
+    Ok(username)
+}
+/// A device logged in as some user,as listed by `obsidiansyncd user sessions`.
+#[derive(Debug)]
+pub struct Session {
+    pub id: i64,
+    pub device: String,
+    pub created: i64,
+    pub last_used: i64,
+    pub last_ip: String,
+    pub expires: Option<i64>,
+}
+pub fn session_list<P: AsRef<Path>>(username: &str, dbpath: P) -> Result<Vec<Session>, UserError> {
+    let sql = "SELECT id, device, created, last_used, last_ip, expires FROM sessions
+WHERE username=? ORDER BY last_used DESC";
+    let conn = Connection::open(dbpath)?;
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map([username], |r| {
+        Ok(Session {
+            id: r.get(0)?,
+            device: r.get(1)?,
+            created: r.get(2)?,
+            last_used: r.get(3)?,
+            last_ip: r.get(4)?,
+            expires: r.get(5)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<Vec<_>, _>>()?)
+}
+/// Log out one device,the password stays the same.Return false if there is no such session.
+pub fn revoke_session<P: AsRef<Path>>(id: i64, dbpath: P) -> Result<bool, UserError> {
+    let conn = Connection::open(dbpath)?;
+    let n = conn.execute("DELETE FROM sessions WHERE id=?", [id])?;
+    Ok(n > 0)
+}
+/// Create the account from config on first run,that is when there is no user in auth db.
+pub fn bootstrap_account<P: AsRef<Path>>(account: &Account, dbpath: P) -> Result<(), UserError> {
+    if user_list(&dbpath)?.is_some() {
+        return Ok(());
+    }
+    match (account.username(), account.password()) {
+        (Some(username), Some(password)) => {
+            add_user_to_auth_db(&username, &password, &dbpath)?;
+            log::info!("created first user {username}");
+        }
+        _ => log::warn!("no user yet,add one with: obsidiansyncd user -a username password"),
+    }
+    Ok(())
+}
 /// command-line user management
 pub fn user_manage<P: AsRef<Path>>(cmd: &UserCommand, dbpath: P) -> Result<(), UserError> {
     match cmd {
@@ -112,6 +253,9 @@ pub fn user_manage<P: AsRef<Path>>(cmd: &UserCommand, dbpath: P) -> Result<(), U
             del,
             pass,
             list,
+            trash,
+            undelete,
+            session,
         } => {
             if let Some(account) = add {
                 add_user(account, &dbpath)?;
@@ -130,6 +274,38 @@ pub fn user_manage<P: AsRef<Path>>(cmd: &UserCommand, dbpath: P) -> Result<(), U
                     v.into_iter().for_each(|i| println!("{i}"));
                 }
             }
+            if let Some(username) = trash {
+                let db = user_db(username, &dbpath)?;
+                for e in db.trash()?.files {
+                    println!("{}\tdeleted by {} at {}", e.path, e.deleted_by, e.deleted_at);
+                }
+            }
+            if let Some(args) = undelete {
+                let db = user_db(&args[0], &dbpath)?;
+                let restored = db.undelete(UndeleteRequest {
+                    paths: args[1..].to_vec(),
+                })?;
+                restored.iter().for_each(|f| println!("restored {}", f.path));
+            }
+            match session {
+                Some(SessionCommand::Sessions { username }) => {
+                    for s in session_list(username, &dbpath)? {
+                        let expires = s.expires.map_or("never".to_string(), |e| e.to_string());
+                        println!(
+                            "{}\t{}\tlast seen {} from {}\tlogged in {}\texpires {}",
+                            s.id, s.device, s.last_used, s.last_ip, s.created, expires
+                        );
+                    }
+                }
+                Some(SessionCommand::Revoke { id }) => {
+                    if revoke_session(*id, &dbpath)? {
+                        println!("revoked session {id}");
+                    } else {
+                        println!("no session {id}");
+                    }
+                }
+                None => {}
+            }
         }
     }
 
@@ -155,7 +331,37 @@ pub fn user_exists<P: AsRef<Path>>(username: &str, dbpath: P) -> Result<bool, Us
         _ => Ok(false),
     }
 }
-fn create_pass_hash(username: &str, password: &str, salt: &str) -> String {
+/// Argon2id hash of password in PHC string format,salt and parameters included
+fn create_pass_hash(password: &str) -> Result<String, UserError> {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt)
+        .map_err(|e| UserError::Authentication(e.to_string()))?;
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| UserError::Authentication(e.to_string()))?;
+    Ok(hash.to_string())
+}
+/// Argon2 hash of no real password,checked in place of a user that doesn't exist
+fn dummy_pass_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| create_pass_hash("").unwrap_or_default())
+}
+/// Check password against an Argon2 PHC string,or the sha256 hash of older versions.
+fn verify_pass_hash(username: &str, password: &str, hash: &str) -> bool {
+    if !hash.starts_with("$argon2") {
+        let computed = compute_hash(username, password, hash);
+        return hash.len() > 16 && constant_time_eq(computed.as_bytes(), hash.as_bytes());
+    }
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+/// sha256 over username,password and salt,as stored by older versions
+fn legacy_pass_hash(username: &str, password: &str, salt: &str) -> String {
     // create a Sha256 object
     let mut hasher = Sha256::new();
     // write input message
@@ -167,9 +373,98 @@ fn create_pass_hash(username: &str, password: &str, salt: &str) -> String {
     let pass_hash = format!("{result:x}{salt}");
     pass_hash
 }
+/// compare without stopping at the first differing byte,so that timing doesn't leak
+/// how much of a hash matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
 /// extract salt from a hash which is the last 16 characters
-pub fn compute_hash(username: &str, password: &str, hash: &str) -> String {
-    let salt = &hash[(hash.chars().count() - 16)..];
+fn compute_hash(username: &str, password: &str, hash: &str) -> String {
+    let salt = hash.get(hash.len().saturating_sub(16)..).unwrap_or_default();
+
+    legacy_pass_hash(username, password, salt)
+}
+
+#[test]
+fn verify_legacy_and_argon2_hash() {
+    let legacy = legacy_pass_hash("bob", "pw", "0123456789abcdef");
+    assert!(verify_pass_hash("bob", "pw", &legacy));
+    assert!(!verify_pass_hash("bob", "bad", &legacy));
+    let hash = create_pass_hash("pw").unwrap();
+    assert!(hash.starts_with("$argon2id$"));
+    assert!(verify_pass_hash("bob", "pw", &hash));
+    assert!(!verify_pass_hash("bob", "bad", &hash));
+}
+
+#[test]
+fn compare_hashes_in_constant_time() {
+    assert!(constant_time_eq(b"abcd", b"abcd"));
+    assert!(!constant_time_eq(b"abcd", b"abce"));
+    assert!(!constant_time_eq(b"abcd", b"abc"));
+    assert!(dummy_pass_hash().starts_with("$argon2id$"));
+}
+
+#[cfg(test)]
+fn auth_db_with_bob(dir: &Path) -> PathBuf {
+    let dbpath = dir.join("auth.db");
+    create_auth_db(&dbpath).unwrap();
+    add_user_to_auth_db("bob", "pw", &dbpath).unwrap();
+    dbpath
+}
+
+#[test]
+fn login_issues_session_tokens() {
+    let dir = tempfile::tempdir().unwrap();
+    let dbpath = auth_db_with_bob(dir.path());
+    let pc = login("bob", "pw", "pc", "10.0.0.1", 0, &dbpath).unwrap().unwrap();
+    let phone = login("bob", "pw", "phone", "10.0.0.2", 0, &dbpath).unwrap().unwrap();
+    assert_ne!(pc, phone);
+    assert_eq!(session_user(&pc, "", &dbpath).unwrap().as_deref(), Some("bob"));
+    assert_eq!(session_user(&phone, "", &dbpath).unwrap().as_deref(), Some("bob"));
+    assert!(login("bob", "bad", "pc", "", 0, &dbpath).unwrap().is_none());
+    assert!(login("alice", "pw", "pc", "", 0, &dbpath).unwrap().is_none());
+    assert!(session_user("0123", "", &dbpath).unwrap().is_none());
+    // only the hash of a token is stored
+    let conn = Connection::open(&dbpath).unwrap();
+    let stored: Option<String> = conn
+        .query_row("SELECT token FROM sessions WHERE token=?", [&pc], |r| r.get(0))
+        .optional()
+        .unwrap();
+    assert!(stored.is_none());
+    let sessions = session_list("bob", &dbpath).unwrap();
+    assert_eq!(sessions.len(), 2);
+    assert!(sessions.iter().all(|s| s.expires.is_none()));
+}
+
+#[test]
+fn session_tokens_expire_after_ttl() {
+    let dir = tempfile::tempdir().unwrap();
+    let dbpath = auth_db_with_bob(dir.path());
+    let token = login("bob", "pw", "pc", "", 7, &dbpath).unwrap().unwrap();
+    let session = &session_list("bob", &dbpath).unwrap()[0];
+    assert_eq!(session.expires, Some(session.created + 7 * 24 * 3600 * 1000));
+    assert!(session_user(&token, "", &dbpath).unwrap().is_some());
+    let conn = Connection::open(&dbpath).unwrap();
+    conn.execute("UPDATE sessions SET expires = ?", [now_millis() - 1])
+        .unwrap();
+    assert!(session_user(&token, "", &dbpath).unwrap().is_none());
+    // expired sessions are cleared on the next login
+    login("bob", "pw", "phone", "", 7, &dbpath).unwrap().unwrap();
+    assert_eq!(session_list("bob", &dbpath).unwrap().len(), 1);
+}
 
-    create_pass_hash(username, password, salt)
+#[test]
+fn passwd_and_del_user_drop_sessions() {
+    let dir = tempfile::tempdir().unwrap();
+    let dbpath = auth_db_with_bob(dir.path());
+    add_user_to_auth_db("alice", "pw", &dbpath).unwrap();
+    let bob = login("bob", "pw", "pc", "", 0, &dbpath).unwrap().unwrap();
+    let alice = login("alice", "pw", "pc", "", 0, &dbpath).unwrap().unwrap();
+    passwd(&["bob".to_string(), "pw2".to_string()], &dbpath).unwrap();
+    assert!(session_user(&bob, "", &dbpath).unwrap().is_none());
+    assert!(login("bob", "pw", "pc", "", 0, &dbpath).unwrap().is_none());
+    assert!(login("bob", "pw2", "pc", "", 0, &dbpath).unwrap().is_some());
+    del_user("alice", &dbpath).unwrap();
+    assert!(session_user(&alice, "", &dbpath).unwrap().is_none());
+    assert!(session_list("alice", &dbpath).unwrap().is_empty());
 }